@@ -1,4 +1,4 @@
-use std::{error::Error, fs::File, io::Write, path::Path, time::Duration};
+use std::{error::Error, fs::File, io::Write, path::Path};
 
 use actix_web::dev::ServerHandle;
 use rand::{thread_rng, Rng};
@@ -8,7 +8,7 @@ use dlcommon::{
     http::{get_client, FileDownload},
     operation::Operation,
 };
-use tokio::{spawn, task::JoinHandle, time::sleep};
+use tokio::{spawn, task::JoinHandle};
 
 #[tokio::main]
 async fn main() {
@@ -29,7 +29,7 @@ where
 {
     let dir = tempdir()?;
     let v: Vec<_> = items
-        .into_iter()
+        .iter()
         .map(|s| {
             let url = format!("http://{}/{}", addr, s.as_ref());
             FileDownload::builder()
@@ -42,7 +42,7 @@ where
                 .unwrap()
         })
         .collect();
-    Operation::builder()
+    let report = Operation::builder()
         .client(get_client(None)?)
         .wait_after_download(1)
         .concurrency(5)
@@ -50,6 +50,13 @@ where
         .unwrap()
         .run(&v[..])
         .await?;
+    println!(
+        "downloaded {} of {} items ({} bytes), {} failed",
+        report.succeeded(),
+        report.total(),
+        report.bytes(),
+        report.failed()
+    );
     drop(dir);
     Ok(())
 }
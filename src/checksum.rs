@@ -0,0 +1,409 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use md5::Md5;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+use strum::{Display, EnumString};
+use tokio::{fs::File, io::AsyncReadExt};
+
+use crate::{http::check_status, operation::OperationReport, Error};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, EnumString, Display)]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
+pub enum Algorithm {
+    Md5,
+    #[strum(to_string = "sha1", serialize = "sha-1")]
+    Sha1,
+    #[strum(to_string = "sha256", serialize = "sha-256")]
+    Sha256,
+    #[strum(to_string = "sha512", serialize = "sha-512")]
+    Sha512,
+}
+
+impl Algorithm {
+    /// Length of the digest in bytes.
+    pub fn digest_len(&self) -> usize {
+        match self {
+            Self::Md5 => 16,
+            Self::Sha1 => 20,
+            Self::Sha256 => 32,
+            Self::Sha512 => 64,
+        }
+    }
+    /// Guess the algorithm from the length of a hex digest.
+    pub fn from_hex_len(len: usize) -> Option<Self> {
+        [Self::Md5, Self::Sha1, Self::Sha256, Self::Sha512]
+            .into_iter()
+            .find(|a| a.digest_len() * 2 == len)
+    }
+    pub fn hasher(&self) -> Hasher {
+        match self {
+            Self::Md5 => Hasher::Md5(Md5::new()),
+            Self::Sha1 => Hasher::Sha1(Sha1::new()),
+            Self::Sha256 => Hasher::Sha256(Sha256::new()),
+            Self::Sha512 => Hasher::Sha512(Sha512::new()),
+        }
+    }
+}
+
+/// Incremental digest of one of the supported algorithms.
+pub enum Hasher {
+    Md5(Md5),
+    Sha1(Sha1),
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Md5(h) => h.update(data),
+            Self::Sha1(h) => h.update(data),
+            Self::Sha256(h) => h.update(data),
+            Self::Sha512(h) => h.update(data),
+        }
+    }
+    pub fn finalize(self) -> Vec<u8> {
+        match self {
+            Self::Md5(h) => h.finalize().to_vec(),
+            Self::Sha1(h) => h.finalize().to_vec(),
+            Self::Sha256(h) => h.finalize().to_vec(),
+            Self::Sha512(h) => h.finalize().to_vec(),
+        }
+    }
+    /// Feed the first `limit` bytes of `path` (or all of it) into the hasher.
+    pub async fn update_from_file(&mut self, path: &Path, limit: Option<u64>) -> Result<(), Error> {
+        let f = File::open(path)
+            .await
+            .map_err(Error::io("Could not open file to hash", path))?;
+        let mut f = f.take(limit.unwrap_or(u64::MAX));
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let n = f
+                .read(&mut buf)
+                .await
+                .map_err(Error::io("Could not read file to hash", path))?;
+            if n == 0 {
+                return Ok(());
+            }
+            self.update(&buf[..n]);
+        }
+    }
+}
+
+/// An expected digest, written as `algorithm:hex` (e.g. `sha256:9f86d0...`).
+/// `algorithm=hex` is also accepted, as is a bare hex digest when the
+/// algorithm can be inferred from its length.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Checksum {
+    pub algorithm: Algorithm,
+    pub digest: Vec<u8>,
+}
+
+impl Checksum {
+    pub fn new(algorithm: Algorithm, hex: &str) -> Result<Self, Error> {
+        let invalid = |reason| Error::InvalidChecksum {
+            value: hex.to_string(),
+            reason,
+        };
+        let digest = hex::decode(hex.trim()).map_err(|_| invalid("not valid hex"))?;
+        if digest.len() != algorithm.digest_len() {
+            return Err(invalid("wrong length for algorithm"));
+        }
+        Ok(Self { algorithm, digest })
+    }
+    pub fn hasher(&self) -> Hasher {
+        self.algorithm.hasher()
+    }
+    pub fn matches(&self, digest: &[u8]) -> bool {
+        self.digest == digest
+    }
+    pub async fn verify_file(&self, path: &Path) -> Result<bool, Error> {
+        let mut h = self.hasher();
+        h.update_from_file(path, None).await?;
+        Ok(self.matches(&h.finalize()))
+    }
+}
+
+impl FromStr for Checksum {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        match s.split_once([':', '=']) {
+            Some((algo, hex)) => Self::new(
+                algo.parse().map_err(|_| Error::InvalidChecksum {
+                    value: s.to_string(),
+                    reason: "unknown algorithm",
+                })?,
+                hex,
+            ),
+            None => Self::new(
+                Algorithm::from_hex_len(s.len()).ok_or_else(|| Error::InvalidChecksum {
+                    value: s.to_string(),
+                    reason: "cannot infer algorithm",
+                })?,
+                s,
+            ),
+        }
+    }
+}
+
+impl Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm, hex::encode(&self.digest))
+    }
+}
+
+impl TryFrom<String> for Checksum {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Checksum> for String {
+    fn from(c: Checksum) -> Self {
+        c.to_string()
+    }
+}
+
+/// Checksums for a set of files keyed by file name, as published in
+/// `SHA256SUMS`-style files or `.sha256`/`.md5` sidecars.
+#[derive(Debug, Clone, Default)]
+pub struct ChecksumManifest {
+    entries: HashMap<String, Checksum>,
+}
+
+impl ChecksumManifest {
+    /// Parse coreutils (`hex  name`, `hex *name`), BSD (`SHA256 (name) = hex`)
+    /// or bare (`hex`) lines. `algorithm` is inferred from the digest length
+    /// when not given; bare digests are recorded under `default_name`.
+    pub fn parse(
+        text: &str,
+        algorithm: Option<Algorithm>,
+        default_name: Option<&str>,
+    ) -> Result<Self, Error> {
+        let mut entries = HashMap::new();
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let malformed = || Error::InvalidChecksum {
+                value: line.to_string(),
+                reason: "malformed checksum manifest line",
+            };
+            let (algo, name, hex) = if let Some((lhs, hex)) = line.split_once(") = ") {
+                let (algo, name) = lhs.split_once(" (").ok_or_else(malformed)?;
+                (Some(algo.parse().map_err(|_| malformed())?), name, hex)
+            } else {
+                match line.split_once(char::is_whitespace) {
+                    Some((hex, name)) => (None, name.trim_start().trim_start_matches('*'), hex),
+                    None => (None, default_name.ok_or_else(malformed)?, line),
+                }
+            };
+            let algo = algo
+                .or(algorithm)
+                .or_else(|| Algorithm::from_hex_len(hex.len()))
+                .ok_or_else(malformed)?;
+            entries.insert(manifest_key(name).to_string(), Checksum::new(algo, hex)?);
+        }
+        Ok(Self { entries })
+    }
+    /// Fetch and parse a manifest. The algorithm is guessed from the manifest's
+    /// file name (`SHA256SUMS`, `foo.iso.md5`, ...) and a sidecar's bare digest
+    /// is attributed to the file name without its extension.
+    pub async fn fetch(client: &Client, url: &str) -> Result<Self, Error> {
+        let r = client
+            .get(url)
+            .send()
+            .await
+            .map_err(|source| Error::Request {
+                url: url.to_string(),
+                source,
+            })?;
+        let r = check_status(r, false)?;
+        let manifest_name = r
+            .url()
+            .path_segments()
+            .and_then(|mut s| s.next_back())
+            .map(str::to_string);
+        let text = r.text().await.map_err(|source| Error::Stream {
+            url: url.to_string(),
+            source,
+        })?;
+        let lower = manifest_name.as_deref().unwrap_or("").to_ascii_lowercase();
+        let algorithm = [
+            ("sha512", Algorithm::Sha512),
+            ("sha256", Algorithm::Sha256),
+            ("sha1", Algorithm::Sha1),
+            ("md5", Algorithm::Md5),
+        ]
+        .into_iter()
+        .find_map(|(s, a)| lower.contains(s).then_some(a));
+        let default_name = manifest_name
+            .as_deref()
+            .and_then(|n| n.rsplit_once('.'))
+            .map(|(stem, _)| stem);
+        Self::parse(&text, algorithm, default_name)
+    }
+    pub fn get(&self, name: &str) -> Option<&Checksum> {
+        self.entries.get(manifest_key(name))
+    }
+    pub fn insert(&mut self, name: &str, checksum: Checksum) {
+        self.entries
+            .insert(manifest_key(name).to_string(), checksum);
+    }
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+    /// Check each file against the entry for its file name.
+    pub async fn verify_files<I, P>(&self, paths: I) -> Verification
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        let mut v = Verification::default();
+        for p in paths {
+            let p = p.as_ref();
+            let status = match p.file_name().and_then(|n| self.get(&n.to_string_lossy())) {
+                None => VerifyStatus::Missing,
+                Some(expected) => {
+                    let mut h = expected.hasher();
+                    match h.update_from_file(p, None).await {
+                        Err(e) => VerifyStatus::Error(e),
+                        Ok(()) => {
+                            let actual = h.finalize();
+                            if expected.matches(&actual) {
+                                VerifyStatus::Ok
+                            } else {
+                                VerifyStatus::Mismatch {
+                                    expected: expected.clone(),
+                                    actual: hex::encode(actual),
+                                }
+                            }
+                        }
+                    }
+                }
+            };
+            v.results.push((p.to_owned(), status));
+        }
+        v
+    }
+    /// Check the files successfully produced by an `Operation::run`.
+    pub async fn verify_report(&self, report: &OperationReport) -> Verification {
+        self.verify_files(report.items.iter().filter_map(|i| i.path()))
+            .await
+    }
+}
+
+fn manifest_key(name: &str) -> &str {
+    name.rsplit(['/', '\\']).next().unwrap_or(name)
+}
+
+#[derive(Debug)]
+pub enum VerifyStatus {
+    Ok,
+    Mismatch {
+        expected: Checksum,
+        actual: String,
+    },
+    /// The manifest has no entry for this file.
+    Missing,
+    Error(Error),
+}
+
+#[derive(Debug, Default)]
+pub struct Verification {
+    pub results: Vec<(PathBuf, VerifyStatus)>,
+}
+
+impl Verification {
+    pub fn is_ok(&self) -> bool {
+        self.results
+            .iter()
+            .all(|(_, s)| matches!(s, VerifyStatus::Ok))
+    }
+    pub fn mismatched(&self) -> impl Iterator<Item = &Path> {
+        self.with(|s| matches!(s, VerifyStatus::Mismatch { .. }))
+    }
+    pub fn missing(&self) -> impl Iterator<Item = &Path> {
+        self.with(|s| matches!(s, VerifyStatus::Missing))
+    }
+    fn with<F>(&self, f: F) -> impl Iterator<Item = &Path>
+    where
+        F: Fn(&VerifyStatus) -> bool,
+    {
+        self.results
+            .iter()
+            .filter(move |(_, s)| f(s))
+            .map(|(p, _)| p.as_path())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MD5: &str = "d41d8cd98f00b204e9800998ecf8427e";
+    const SHA1: &str = "da39a3ee5e6b4b0d3255bfef95601890afd80709";
+    const SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    #[test]
+    fn checksum_from_str() {
+        let c: Checksum = format!("sha256:{SHA256}").parse().unwrap();
+        assert_eq!(c.algorithm, Algorithm::Sha256);
+        assert_eq!(c.to_string(), format!("sha256:{SHA256}"));
+        let c: Checksum = format!(" SHA-1={SHA1} ").parse().unwrap();
+        assert_eq!(c.algorithm, Algorithm::Sha1);
+        let c: Checksum = MD5.parse().unwrap();
+        assert_eq!(c.algorithm, Algorithm::Md5);
+
+        assert!(format!("crc32:{MD5}").parse::<Checksum>().is_err());
+        assert!(format!("sha256:{MD5}").parse::<Checksum>().is_err());
+        assert!("md5:not hex".parse::<Checksum>().is_err());
+        assert!("abcd".parse::<Checksum>().is_err());
+    }
+
+    #[test]
+    fn manifest_coreutils() {
+        let text = format!("# comment\n{SHA256}  a.iso\n{SHA256} *dir/b.img\n\n{MD5}  c\n");
+        let m = ChecksumManifest::parse(&text, None, None).unwrap();
+        assert_eq!(m.len(), 3);
+        assert_eq!(m.get("a.iso").unwrap().algorithm, Algorithm::Sha256);
+        assert!(m.get("b.img").is_some());
+        assert!(m.get("/elsewhere/b.img").is_some());
+        assert_eq!(m.get("c").unwrap().algorithm, Algorithm::Md5);
+        assert!(m.get("d").is_none());
+    }
+
+    #[test]
+    fn manifest_bsd() {
+        let text = format!("SHA256 (a.iso) = {SHA256}\nMD5 (c d) = {MD5}\n");
+        let m = ChecksumManifest::parse(&text, None, None).unwrap();
+        assert_eq!(m.get("a.iso").unwrap().algorithm, Algorithm::Sha256);
+        assert_eq!(m.get("c d").unwrap().algorithm, Algorithm::Md5);
+        let text = format!("SHA1 (a.iso) = {SHA256}\n");
+        assert!(ChecksumManifest::parse(&text, None, None).is_err());
+    }
+
+    #[test]
+    fn manifest_bare() {
+        let m = ChecksumManifest::parse(SHA1, None, Some("a.iso")).unwrap();
+        assert_eq!(m.get("a.iso").unwrap().algorithm, Algorithm::Sha1);
+        assert!(ChecksumManifest::parse(SHA1, None, None).is_err());
+        assert!(ChecksumManifest::parse(SHA1, Some(Algorithm::Sha256), Some("a")).is_err());
+    }
+}
@@ -0,0 +1,174 @@
+use std::path::PathBuf;
+
+use clap::{builder::RangedU64ValueParser, Args};
+use reqwest_cookie_store::CookieStoreMutex;
+
+use crate::cookies::{load_cookies, save_cookies, Browser, BrowserOptions, CookieSource};
+use crate::http::{FileDownloadBuilder, OverwriteBehaviour, UsagePref};
+use crate::operation::OperationBuilder;
+use crate::retry::RetryPolicy;
+use crate::Error;
+
+/// Flags for loading cookies from a browser, files and headers.
+#[derive(Debug, Clone, Default, Args)]
+pub struct CookieArgs {
+    /// Load cookies from this browser.
+    #[arg(short, long, value_enum)]
+    pub browser: Option<Browser>,
+    /// Read browser cookies from this profile instead of the default one.
+    #[arg(long, value_name = "NAME", requires = "browser")]
+    pub browser_profile: Option<String>,
+    /// Read browser cookies from this cookie database.
+    #[arg(long, value_name = "FILE", requires = "browser")]
+    pub browser_cookie_db: Option<PathBuf>,
+    /// Load cookies from a Netscape cookies.txt file, or a JSON export if
+    /// the name ends in `.json`.
+    #[arg(long = "cookies", value_name = "FILE")]
+    pub cookie_files: Vec<PathBuf>,
+    /// Send the cookies of a `Cookie:` header to a domain.
+    #[arg(long = "cookie-header", value_name = "DOMAIN: HEADER", value_parser = parse_cookie_header)]
+    pub cookie_headers: Vec<(String, String)>,
+    /// Load cookies saved by the last run from this file, and save the
+    /// cookies of this run to it. JSON if the name ends in `.json`,
+    /// otherwise Netscape.
+    #[arg(long, value_name = "FILE")]
+    pub cookie_jar: Option<PathBuf>,
+    /// Also save cookies that expire with the session to the cookie jar.
+    #[arg(long, requires = "cookie_jar")]
+    pub keep_session_cookies: bool,
+    /// Fail if any cookie cannot be imported, instead of skipping it with a
+    /// warning.
+    #[arg(long)]
+    pub strict_cookies: bool,
+    /// Only load browser and file cookies for these domains.
+    #[arg(long = "cookie-domain", value_name = "DOMAIN")]
+    pub cookie_domains: Vec<String>,
+}
+
+fn parse_cookie_header(s: &str) -> Result<(String, String), String> {
+    s.split_once(':')
+        .map(|(d, h)| (d.trim().to_string(), h.trim().to_string()))
+        .filter(|(d, _)| !d.is_empty())
+        .ok_or_else(|| "expected 'DOMAIN: name=value; ...'".to_string())
+}
+
+impl CookieArgs {
+    pub fn browser_options(&self) -> Option<BrowserOptions> {
+        let mut b = BrowserOptions::builder();
+        b.browser(self.browser?);
+        if let Some(p) = &self.browser_profile {
+            b.profile(p.clone());
+        }
+        if let Some(p) = &self.browser_cookie_db {
+            b.path(p.clone());
+        }
+        Some(b.build().expect("all fields have defaults"))
+    }
+    pub fn sources(&self) -> Vec<CookieSource> {
+        self.browser_options()
+            .map(CookieSource::Browser)
+            .into_iter()
+            .chain(self.cookie_jar.clone().map(CookieSource::Saved))
+            .chain(self.cookie_files.iter().map(CookieSource::file))
+            .chain(
+                self.cookie_headers
+                    .iter()
+                    .map(|(domain, header)| CookieSource::Header {
+                        domain: domain.clone(),
+                        header: header.clone(),
+                    }),
+            )
+            .collect()
+    }
+    /// Save `store` to the cookie jar, if one was given.
+    pub fn save(&self, store: &CookieStoreMutex) -> Result<(), Error> {
+        if let Some(jar) = &self.cookie_jar {
+            let n = save_cookies(store, jar, self.keep_session_cookies)?;
+            log::debug!("Saved {n} cookies to '{}'", jar.display());
+        }
+        Ok(())
+    }
+}
+
+/// Empty when no source is given.
+impl TryFrom<&CookieArgs> for CookieStoreMutex {
+    type Error = Error;
+
+    fn try_from(args: &CookieArgs) -> Result<Self, Error> {
+        let domains = (!args.cookie_domains.is_empty()).then(|| args.cookie_domains.clone());
+        let imported = load_cookies(&args.sources(), domains, args.strict_cookies)?;
+        for w in &imported.warnings {
+            log::warn!("Skipping cookie: {w}");
+        }
+        Ok(imported.store)
+    }
+}
+
+/// Flags for `OperationBuilder`. The client is left to the caller.
+#[derive(Debug, Clone, Args)]
+pub struct OperationArgs {
+    /// Number of downloads to run at once.
+    #[arg(short = 'j', long, default_value_t = 1, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub concurrency: usize,
+    /// Seconds to wait after each download.
+    #[arg(short, long, default_value_t = 0, value_name = "SECS")]
+    pub wait: u64,
+    /// Total attempts per download.
+    #[arg(long, default_value_t = 3)]
+    pub attempts: u32,
+}
+
+impl From<&OperationArgs> for OperationBuilder {
+    fn from(args: &OperationArgs) -> Self {
+        let mut b = OperationBuilder::default();
+        b.concurrency(args.concurrency)
+            .wait_after_download(args.wait)
+            .retry(
+                RetryPolicy::builder()
+                    .max_attempts(args.attempts)
+                    .build()
+                    .expect("all fields have defaults"),
+            );
+        b
+    }
+}
+
+/// Flags for the `FileDownloadBuilder` settings shared by all downloads.
+#[derive(Debug, Clone, Args)]
+pub struct DownloadArgs {
+    /// Directory to download into.
+    #[arg(short = 'd', long, default_value = ".")]
+    pub target: PathBuf,
+    /// What to do when a file already exists.
+    #[arg(short, long, value_enum, default_value_t)]
+    pub overwrite: OverwriteBehaviour,
+    /// Whether to name files from the Content-Disposition header.
+    #[arg(long, value_enum, default_value_t)]
+    pub content_disposition: UsagePref,
+    /// Whether to name files from the final URL after redirects.
+    #[arg(long, value_enum, default_value_t)]
+    pub final_url: UsagePref,
+    /// Send a HEAD request before each download.
+    #[arg(long)]
+    pub preflight_head: bool,
+    /// Keep partial downloads and continue them on the next run.
+    #[arg(short, long)]
+    pub resume: bool,
+    /// Fetch large files over up to this many connections at once.
+    #[arg(long, default_value_t = 1, value_name = "N")]
+    pub segments: usize,
+}
+
+impl From<&DownloadArgs> for FileDownloadBuilder {
+    fn from(args: &DownloadArgs) -> Self {
+        let mut b = FileDownloadBuilder::default();
+        b.target(args.target.clone())
+            .overwrite(args.overwrite)
+            .filename_use_content_disposition(args.content_disposition)
+            .filename_use_final_url(args.final_url)
+            .preflight_head(args.preflight_head)
+            .resume(args.resume)
+            .segments(args.segments);
+        b
+    }
+}
@@ -0,0 +1,200 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use tokio::sync::{watch, Semaphore};
+use tokio_util::sync::CancellationToken;
+
+use crate::http::FileDownload;
+use crate::operation::{DownloadReport, OperationReport};
+use crate::reporter::Reporter;
+
+/// Holds downloads while paused.
+#[derive(Debug, Clone)]
+pub(crate) struct PauseGate(Arc<watch::Sender<bool>>);
+
+impl Default for PauseGate {
+    fn default() -> Self {
+        Self(Arc::new(watch::Sender::new(false)))
+    }
+}
+
+impl PauseGate {
+    fn set(&self, paused: bool) {
+        self.0.send_replace(paused);
+    }
+    pub(crate) fn is_paused(&self) -> bool {
+        *self.0.borrow()
+    }
+    /// Return once not paused.
+    pub(crate) async fn wait(&self) {
+        if self.is_paused() {
+            let _ = self.0.subscribe().wait_for(|p| !p).await;
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    queued: AtomicUsize,
+    active: AtomicUsize,
+    finished: AtomicUsize,
+}
+
+/// A snapshot of a running `Operation`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ControlState {
+    pub paused: bool,
+    pub cancelled: bool,
+    pub concurrency: usize,
+    /// Downloads waiting to be dispatched.
+    pub queued: usize,
+    pub active: usize,
+    pub finished: usize,
+}
+
+/// Controls an `Operation` while `run` is in progress, from `Operation::control`.
+#[derive(Debug, Clone)]
+pub struct OperationControl {
+    pub(crate) gate: PauseGate,
+    pub(crate) semaphore: Arc<Semaphore>,
+    pub(crate) limit: Arc<AtomicUsize>,
+    pub(crate) debt: Arc<AtomicUsize>,
+    pub(crate) token: CancellationToken,
+    pub(crate) counters: Arc<Counters>,
+}
+
+impl OperationControl {
+    /// Stop dispatching downloads and stall running ones between chunks.
+    /// Connections stay open, so a long pause may make servers give up on
+    /// them; resumable downloads then continue from their partial file.
+    pub fn pause(&self) {
+        self.gate.set(true);
+    }
+    pub fn resume(&self) {
+        self.gate.set(false);
+    }
+    pub fn is_paused(&self) -> bool {
+        self.gate.is_paused()
+    }
+    pub fn cancel(&self) {
+        self.token.cancel();
+    }
+    pub fn concurrency(&self) -> usize {
+        self.limit.load(Ordering::Relaxed)
+    }
+    /// Change how many downloads run at once. Lowering it takes effect as
+    /// running downloads finish.
+    pub fn set_concurrency(&self, n: usize) {
+        let old = self.limit.swap(n, Ordering::Relaxed);
+        if n > old {
+            // cancel permits still owed before adding new ones
+            let more = n - old;
+            let owed = self
+                .debt
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |d| {
+                    Some(d.saturating_sub(more))
+                })
+                .unwrap();
+            self.semaphore.add_permits(more - owed.min(more));
+        } else if n < old {
+            // permits held by running downloads are forgotten by the
+            // dispatcher as they are returned
+            let rest = (old - n) - self.semaphore.forget_permits(old - n);
+            self.debt.fetch_add(rest, Ordering::Relaxed);
+        }
+    }
+    pub fn state(&self) -> ControlState {
+        ControlState {
+            paused: self.is_paused(),
+            cancelled: self.token.is_cancelled(),
+            concurrency: self.concurrency(),
+            queued: self.counters.queued.load(Ordering::Relaxed),
+            active: self.counters.active.load(Ordering::Relaxed),
+            finished: self.counters.finished.load(Ordering::Relaxed),
+        }
+    }
+    pub(crate) fn reporter(&self) -> Arc<dyn Reporter> {
+        Arc::new(ControlReporter(self.counters.clone()))
+    }
+}
+
+struct ControlReporter(Arc<Counters>);
+
+impl Reporter for ControlReporter {
+    fn begin(&self, _total: Option<u64>) {
+        self.0.queued.store(0, Ordering::Relaxed);
+        self.0.active.store(0, Ordering::Relaxed);
+        self.0.finished.store(0, Ordering::Relaxed);
+    }
+    fn queued(&self, _id: usize, _download: &FileDownload) {
+        self.0.queued.fetch_add(1, Ordering::Relaxed);
+    }
+    fn started(&self, _id: usize, _download: &FileDownload) {
+        self.0.queued.fetch_sub(1, Ordering::Relaxed);
+        self.0.active.fetch_add(1, Ordering::Relaxed);
+    }
+    fn finished(&self, _id: usize, _report: &DownloadReport) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+        self.0.finished.fetch_add(1, Ordering::Relaxed);
+    }
+    fn end(&self, _report: &OperationReport) {
+        self.0.queued.store(0, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tempfile::tempdir;
+    use tokio::{spawn, time::sleep};
+
+    use crate::http::get_client;
+    use crate::operation::Operation;
+    use crate::test_util::{serve, Served};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn concurrency_changes_while_running() {
+        let mut served = Served::new(vec![0; 10]);
+        served.delay = Duration::from_millis(100);
+        let server = serve(served);
+        let dir = tempdir().unwrap();
+        let downloads: Vec<_> = (0..20)
+            .map(|i| {
+                FileDownload::builder()
+                    .url(format!("{}/f{i}", server.url))
+                    .target(dir.path().to_owned())
+                    .filename(Some(format!("f{i}")))
+                    .title(None)
+                    .build()
+                    .unwrap()
+            })
+            .collect();
+        let op = Operation::builder()
+            .client(get_client(None).unwrap())
+            .concurrency(4)
+            .build()
+            .unwrap();
+        let control = op.control();
+        let served = server.served.clone();
+        let check = spawn(async move {
+            sleep(Duration::from_millis(30)).await;
+            // all four are running, so three permits are owed
+            control.set_concurrency(1);
+            sleep(Duration::from_millis(30)).await;
+            control.set_concurrency(3);
+            assert_eq!(control.concurrency(), 3);
+            // once the first four have finished
+            sleep(Duration::from_millis(120)).await;
+            served.reset_max();
+        });
+        let report = op.run(downloads).await.unwrap();
+        check.await.unwrap();
+        assert_eq!(report.succeeded(), 20);
+        assert_eq!(server.served.max_active.load(Ordering::SeqCst), 3);
+    }
+}
@@ -1,17 +1,32 @@
-use std::error::Error;
+use std::{
+    env::var_os,
+    fs::{read_to_string, rename, write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
 
 use clap::ValueEnum;
+use cookie::SameSite;
+use cookie_store::{Cookie as StoredCookie, CookieDomain, CookieExpiration};
+use derive_builder::Builder;
 use reqwest::Url;
 use reqwest_cookie_store::{CookieStore, CookieStoreMutex, RawCookie};
 #[cfg(target_os = "macos")]
 use rookie::safari;
-use rookie::{brave, chrome, edge, enums::Cookie, firefox, opera};
+use rookie::{any_browser, brave, chrome, edge, enums::Cookie, firefox, opera};
+use serde::{Deserialize, Serialize};
 use strum::{Display, EnumString};
 use time::OffsetDateTime;
 
+use crate::error::BoxError;
+use crate::file::temp_path;
+use crate::Error;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, Display, Default, ValueEnum)]
 #[strum(serialize_all = "lowercase")]
 pub enum Browser {
+    /// The first installed browser with cookies for the requested domains.
+    Auto,
     Brave,
     Chrome,
     Edge,
@@ -22,48 +37,689 @@ pub enum Browser {
     Safari,
 }
 
+/// The order `Browser::Auto` tries browsers in.
+const AUTO_ORDER: &[Browser] = &[
+    Browser::Firefox,
+    Browser::Chrome,
+    Browser::Edge,
+    Browser::Brave,
+    Browser::Opera,
+    #[cfg(target_os = "macos")]
+    Browser::Safari,
+];
+
+#[cfg(windows)]
+const CHROMIUM_DIRS: [(Browser, &str); 3] = [
+    (Browser::Brave, "BraveSoftware/Brave-Browser/User Data"),
+    (Browser::Chrome, "Google/Chrome/User Data"),
+    (Browser::Edge, "Microsoft/Edge/User Data"),
+];
+#[cfg(target_os = "macos")]
+const CHROMIUM_DIRS: [(Browser, &str); 3] = [
+    (Browser::Brave, "BraveSoftware/Brave-Browser"),
+    (Browser::Chrome, "Google/Chrome"),
+    (Browser::Edge, "Microsoft Edge"),
+];
+#[cfg(not(any(windows, target_os = "macos")))]
+const CHROMIUM_DIRS: [(Browser, &str); 3] = [
+    (Browser::Brave, "BraveSoftware/Brave-Browser"),
+    (Browser::Chrome, "google-chrome"),
+    (Browser::Edge, "microsoft-edge"),
+];
+
+// where Firefox keeps profiles.ini, and where Chromium browsers keep theirs
+#[cfg(windows)]
+fn profile_roots() -> Option<(PathBuf, PathBuf)> {
+    let roaming = PathBuf::from(var_os("APPDATA")?);
+    Some((
+        roaming.join("Mozilla/Firefox"),
+        PathBuf::from(var_os("LOCALAPPDATA")?),
+    ))
+}
+#[cfg(target_os = "macos")]
+fn profile_roots() -> Option<(PathBuf, PathBuf)> {
+    let support = PathBuf::from(var_os("HOME")?).join("Library/Application Support");
+    Some((support.join("Firefox"), support))
+}
+#[cfg(not(any(windows, target_os = "macos")))]
+fn profile_roots() -> Option<(PathBuf, PathBuf)> {
+    let home = PathBuf::from(var_os("HOME")?);
+    Some((home.join(".mozilla/firefox"), home.join(".config")))
+}
+
 impl Browser {
     // pub fn as_str(&self) -> &'static str {
     //     match self {
     //         Self::Firefox => "firefox",
     //     }
     // }
-    fn get_cookies(&self, domains: Option<Vec<String>>) -> Result<Vec<Cookie>, Box<dyn Error>> {
-        Ok(match self {
-            Self::Brave => brave(domains)?,
-            Self::Edge => edge(domains)?,
-            Self::Firefox => firefox(domains)?,
-            Self::Chrome => chrome(domains)?,
-            Self::Opera => opera(domains)?,
+    fn get_cookies(&self, domains: Option<Vec<String>>) -> Result<Vec<Cookie>, Error> {
+        match self {
+            Self::Auto => return BrowserOptions::from(*self).cookies(domains),
+            Self::Brave => brave(domains),
+            Self::Edge => edge(domains),
+            Self::Firefox => firefox(domains),
+            Self::Chrome => chrome(domains),
+            Self::Opera => opera(domains),
             #[cfg(target_os = "macos")]
-            Self::Safari => safari(domains)?,
-        })
+            Self::Safari => safari(domains),
+        }
+        .map_err(|e| self.error(e))
     }
+    fn error<E>(&self, e: E) -> Error
+    where
+        E: Into<BoxError>,
+    {
+        Error::Browser {
+            browser: *self,
+            source: e.into(),
+        }
+    }
+    /// The cookie database of the profile called `name`.
+    fn profile_cookies(&self, name: &str) -> Result<PathBuf, Error> {
+        let (firefox, chromium) =
+            profile_roots().ok_or_else(|| self.error("could not find the home directory"))?;
+        let found = match self {
+            Self::Firefox => firefox_profile(&firefox, name).map(|p| p.join("cookies.sqlite")),
+            _ => CHROMIUM_DIRS
+                .iter()
+                .find(|(b, _)| b == self)
+                .and_then(|(_, dir)| chromium_profile(&chromium.join(dir), name))
+                .map(|p| {
+                    let network = p.join("Network").join("Cookies");
+                    if network.is_file() {
+                        network
+                    } else {
+                        p.join("Cookies")
+                    }
+                }),
+        };
+        found
+            .filter(|p| p.is_file())
+            .ok_or_else(|| self.error(format!("no profile '{name}' with cookies")))
+    }
+}
+
+/// A profile listed in `profiles.ini` by name or directory.
+fn firefox_profile(root: &Path, name: &str) -> Option<PathBuf> {
+    let ini = read_to_string(root.join("profiles.ini")).ok()?;
+    let (mut pname, mut path, mut relative) = (None, None, true);
+    let mut found = None;
+    // a trailing section header flushes the last profile
+    for line in ini.lines().map(str::trim).chain(["[]"]) {
+        if line.starts_with('[') {
+            if let Some(p) = path.take() {
+                let dir = if relative {
+                    root.join(p)
+                } else {
+                    PathBuf::from(p)
+                };
+                let matches = pname == Some(name)
+                    || dir.file_name().is_some_and(|f| f == name)
+                    || dir.extension().is_some_and(|e| e == name);
+                if matches && found.is_none() {
+                    found = Some(dir);
+                }
+            }
+            (pname, relative) = (None, true);
+        } else if let Some((k, v)) = line.split_once('=') {
+            match k.trim() {
+                "Name" => pname = Some(v.trim()),
+                "Path" => path = Some(v.trim()),
+                "IsRelative" => relative = v.trim() != "0",
+                _ => (),
+            }
+        }
+    }
+    found
 }
 
-pub fn get_cookies(
+/// A profile by directory (`Default`, `Profile 1`) or by the display name
+/// recorded in `Local State`.
+fn chromium_profile(root: &Path, name: &str) -> Option<PathBuf> {
+    if root.join(name).is_dir() {
+        return Some(root.join(name));
+    }
+    let state: serde_json::Value =
+        serde_json::from_str(&read_to_string(root.join("Local State")).ok()?).ok()?;
+    state
+        .pointer("/profile/info_cache")?
+        .as_object()?
+        .iter()
+        .find(|(_, info)| info.get("name").and_then(|n| n.as_str()) == Some(name))
+        .map(|(dir, _)| root.join(dir))
+}
+
+/// Which browser profile `get_cookies` reads.
+#[derive(Debug, Clone, Default, Builder)]
+pub struct BrowserOptions {
+    #[builder(default)]
     browser: Browser,
+    /// A Firefox profile name or directory, or a Chromium profile directory
+    /// (`Default`, `Profile 1`) or display name.
+    #[builder(default, setter(into, strip_option))]
+    profile: Option<String>,
+    /// The cookie database to read, e.g. a `cookies.sqlite` or `Cookies`
+    /// file. Takes precedence over `profile`.
+    #[builder(default, setter(into, strip_option))]
+    path: Option<PathBuf>,
+}
+
+impl From<Browser> for BrowserOptions {
+    fn from(browser: Browser) -> Self {
+        Self {
+            browser,
+            ..Default::default()
+        }
+    }
+}
+
+impl BrowserOptions {
+    pub fn builder() -> BrowserOptionsBuilder {
+        BrowserOptionsBuilder::default()
+    }
+    fn cookies(&self, domains: Option<Vec<String>>) -> Result<Vec<Cookie>, Error> {
+        if self.browser == Browser::Auto && self.path.is_none() {
+            for &browser in AUTO_ORDER {
+                let options = Self {
+                    browser,
+                    ..self.clone()
+                };
+                match options.cookies(domains.clone()) {
+                    Ok(c) if !c.is_empty() => {
+                        log::info!("Using cookies from {browser}");
+                        return Ok(c);
+                    }
+                    Ok(_) => log::debug!("No matching cookies in {browser}"),
+                    Err(e) => log::debug!("{e}"),
+                }
+            }
+            return Err(self
+                .browser
+                .error("no installed browser has matching cookies"));
+        }
+        let path = match (&self.path, &self.profile) {
+            (Some(p), _) => p.clone(),
+            (None, Some(name)) => self.browser.profile_cookies(name)?,
+            (None, None) => return self.browser.get_cookies(domains),
+        };
+        let db = path
+            .to_str()
+            .ok_or_else(|| self.browser.error("cookie path is not valid UTF-8"))?;
+        // Chromium browsers on Windows need the key in `Local State`
+        let key = path
+            .ancestors()
+            .map(|a| a.join("Local State"))
+            .find(|k| k.is_file());
+        any_browser(db, domains, key.as_deref().and_then(|k| k.to_str()))
+            .map_err(|e| self.browser.error(e))
+    }
+}
+
+/// Where to load cookies from.
+#[derive(Debug, Clone)]
+pub enum CookieSource {
+    Browser(BrowserOptions),
+    /// A Netscape/Mozilla `cookies.txt` file, as written by curl and yt-dlp.
+    Netscape(PathBuf),
+    /// A JSON array of cookies, as exported by EditThisCookie and similar
+    /// browser extensions.
+    Json(PathBuf),
+    /// The value of a `Cookie:` request header, to send to `domain`.
+    Header {
+        domain: String,
+        header: String,
+    },
+    /// A jar written by `save_cookies`, in the format its extension implies.
+    /// A missing file counts as empty, as on the first run.
+    Saved(PathBuf),
+}
+
+impl CookieSource {
+    /// A `Json` source for `.json` files, otherwise `Netscape`.
+    pub fn file<P>(path: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        let path = path.into();
+        if path
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("json"))
+        {
+            Self::Json(path)
+        } else {
+            Self::Netscape(path)
+        }
+    }
+    /// Read the cookies, keeping only those whose domain contains one of
+    /// `domains`. Header cookies are always kept.
+    pub fn cookies(&self, domains: Option<Vec<String>>) -> Result<Vec<Cookie>, Error> {
+        let keep = |c: &Cookie| {
+            domains
+                .as_ref()
+                .is_none_or(|ds| ds.iter().any(|d| c.domain.contains(d.as_str())))
+        };
+        match self {
+            Self::Browser(b) => b.cookies(domains.clone()),
+            Self::Netscape(path) => Ok(parse_netscape(&read_cookie_file(path)?, path)?
+                .into_iter()
+                .filter(keep)
+                .collect()),
+            Self::Json(path) => Ok(parse_json(&read_cookie_file(path)?, path)?
+                .into_iter()
+                .filter(keep)
+                .collect()),
+            Self::Header { domain, header } => Ok(parse_header(domain, header)),
+            Self::Saved(path) if !path.exists() => Ok(vec![]),
+            Self::Saved(path) => Self::file(path).cookies(domains),
+        }
+    }
+}
+
+fn read_cookie_file(path: &Path) -> Result<String, Error> {
+    read_to_string(path).map_err(Error::io("Could not read cookie file", path))
+}
+
+/// Parse the tab-separated Netscape format: domain, include subdomains,
+/// path, secure, expiry, name and value. Lines starting with `#HttpOnly_`
+/// are HTTP-only cookies; other `#` lines are comments.
+pub fn parse_netscape(text: &str, path: &Path) -> Result<Vec<Cookie>, Error> {
+    let mut cookies = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let (line, http_only) = match line.strip_prefix("#HttpOnly_") {
+            Some(l) => (l, true),
+            None => (line, false),
+        };
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let err = |reason: &str| Error::CookieFile {
+            path: path.to_owned(),
+            line: i + 1,
+            reason: reason.to_string(),
+        };
+        let fields: Vec<_> = line.trim_end_matches(['\r', '\n']).split('\t').collect();
+        let [domain, subdomains, cpath, secure, expires, name, ref rest @ ..] = fields[..] else {
+            return Err(err("expected 7 tab-separated fields"));
+        };
+        let expires: u64 = expires.parse().map_err(|_| err("invalid expiry"))?;
+        // a leading dot is what makes `insert_cookie` match subdomains
+        let domain = if subdomains.eq_ignore_ascii_case("TRUE") && !domain.starts_with('.') {
+            format!(".{domain}")
+        } else {
+            domain.to_string()
+        };
+        cookies.push(Cookie {
+            domain,
+            path: cpath.to_string(),
+            secure: secure.eq_ignore_ascii_case("TRUE"),
+            // 0 marks a session cookie
+            expires: (expires > 0).then_some(expires),
+            name: name.to_string(),
+            value: rest.first().copied().unwrap_or_default().to_string(),
+            http_only,
+            same_site: -1,
+        });
+    }
+    Ok(cookies)
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonCookie {
+    domain: String,
+    name: String,
+    value: String,
+    #[serde(default = "root_path")]
+    path: String,
+    #[serde(default)]
+    secure: bool,
+    #[serde(default)]
+    http_only: bool,
+    #[serde(default)]
+    host_only: bool,
+    #[serde(default, alias = "expires", skip_serializing_if = "Option::is_none")]
+    expiration_date: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    same_site: Option<String>,
+}
+
+impl From<JsonCookie> for Cookie {
+    fn from(c: JsonCookie) -> Self {
+        let domain = c.domain.trim_start_matches('.');
+        Cookie {
+            domain: if c.host_only {
+                domain.to_string()
+            } else {
+                format!(".{domain}")
+            },
+            path: c.path,
+            secure: c.secure,
+            expires: c.expiration_date.map(|e| e as u64),
+            name: c.name,
+            value: c.value,
+            http_only: c.http_only,
+            // as stored by browsers
+            same_site: match c.same_site.as_deref() {
+                Some("no_restriction" | "none") => 0,
+                Some("lax") => 1,
+                Some("strict") => 2,
+                _ => -1,
+            },
+        }
+    }
+}
+
+impl From<&Cookie> for JsonCookie {
+    fn from(c: &Cookie) -> Self {
+        JsonCookie {
+            domain: c.domain.clone(),
+            name: c.name.clone(),
+            value: c.value.clone(),
+            path: c.path.clone(),
+            secure: c.secure,
+            http_only: c.http_only,
+            host_only: !c.domain.starts_with('.'),
+            expiration_date: c.expires.map(|e| e as f64),
+            same_site: match c.same_site {
+                0 => Some("no_restriction".to_string()),
+                1 => Some("lax".to_string()),
+                2 => Some("strict".to_string()),
+                _ => None,
+            },
+        }
+    }
+}
+
+fn root_path() -> String {
+    "/".to_string()
+}
+
+/// Parse a JSON array of cookie objects with `domain`, `name`, `value` and
+/// optionally `path`, `secure`, `httpOnly`, `hostOnly`, `expirationDate` and
+/// `sameSite` fields.
+pub fn parse_json(text: &str, path: &Path) -> Result<Vec<Cookie>, Error> {
+    let cookies: Vec<JsonCookie> = serde_json::from_str(text).map_err(|e| Error::CookieFile {
+        path: path.to_owned(),
+        line: e.line(),
+        reason: e.to_string(),
+    })?;
+    Ok(cookies.into_iter().map(Cookie::from).collect())
+}
+
+/// Split a `Cookie:` header value (`a=1; b=2`) into cookies for `domain`.
+pub fn parse_header(domain: &str, header: &str) -> Vec<Cookie> {
+    header
+        .trim()
+        .trim_start_matches("Cookie:")
+        .split(';')
+        .filter_map(|pair| pair.trim().split_once('='))
+        .map(|(name, value)| Cookie {
+            domain: domain.to_string(),
+            path: "/".to_string(),
+            secure: false,
+            expires: None,
+            name: name.trim().to_string(),
+            value: value.trim().to_string(),
+            http_only: false,
+            same_site: -1,
+        })
+        .collect()
+}
+
+/// Cookies imported into a store, and why any others were skipped.
+#[derive(Debug)]
+pub struct ImportedCookies {
+    pub store: CookieStoreMutex,
+    /// An `Error::Cookie` for each cookie that could not be imported.
+    pub warnings: Vec<Error>,
+}
+
+/// Load cookies from all of `sources` into one store. With `strict` the
+/// first cookie that cannot be imported fails the import; otherwise it is
+/// skipped and listed in `warnings`.
+pub fn load_cookies(
+    sources: &[CookieSource],
+    domains: Option<Vec<String>>,
+    strict: bool,
+) -> Result<ImportedCookies, Error> {
+    let mut cookies = vec![];
+    for source in sources {
+        cookies.extend(source.cookies(domains.clone())?);
+    }
+    store_cookies(cookies, strict)
+}
+
+/// Read cookies from a browser, or from a particular profile of one given
+/// `BrowserOptions`. `strict` is as for `load_cookies`.
+pub fn get_cookies<B>(
+    browser: B,
     domains: Option<Vec<String>>,
-) -> Result<CookieStoreMutex, Box<dyn Error>> {
+    strict: bool,
+) -> Result<ImportedCookies, Error>
+where
+    B: Into<BrowserOptions>,
+{
+    store_cookies(browser.into().cookies(domains)?, strict)
+}
+
+fn store_cookies(cookies: Vec<Cookie>, strict: bool) -> Result<ImportedCookies, Error> {
     let mut cs = CookieStore::new(None);
-    for c in browser.get_cookies(domains)? {
-        cs.insert_raw(
-            &RawCookie::build((&c.name, &c.value))
-                .domain(&c.domain)
-                .secure(c.secure)
-                .http_only(c.http_only)
-                .expires(
-                    c.expires
-                        .map(|i| OffsetDateTime::from_unix_timestamp(i as i64).unwrap()),
-                )
-                .build(),
-            &Url::parse(&format!(
-                "https://{}{}",
-                c.domain.trim_start_matches('.'),
-                &c.path
-            ))?,
-        )
-        .map_err(|e| format!("Got error on {c:?}: {e}"))?;
-    }
-    Ok(CookieStoreMutex::new(cs))
+    let mut warnings = vec![];
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    for c in cookies {
+        // inserting an expired cookie would delete a live one of the same name
+        if c.expires.is_some_and(|e| (e.min(MAX_EXPIRY) as i64) <= now) {
+            log::debug!("Skipping expired cookie '{}' for {}", c.name, c.domain);
+            continue;
+        }
+        match insert_cookie(&mut cs, &c) {
+            Ok(()) => (),
+            Err(e) if strict => return Err(e),
+            Err(e) => warnings.push(e),
+        }
+    }
+    Ok(ImportedCookies {
+        store: CookieStoreMutex::new(cs),
+        warnings,
+    })
+}
+
+// the latest time `OffsetDateTime` can hold, 9999-12-31T23:59:59Z
+const MAX_EXPIRY: u64 = 253_402_300_799;
+
+fn insert_cookie(cs: &mut CookieStore, c: &Cookie) -> Result<(), Error> {
+    // the value is left out, as it is often a credential
+    let err = |source: BoxError| Error::Cookie {
+        cookie: format!("'{}' for {}{}", c.name, c.domain, c.path),
+        source,
+    };
+    let host = c.domain.trim_start_matches('.');
+    // without a leading dot a cookie is only sent to that exact host, as are
+    // cookies for IP addresses
+    let host_only = !c.domain.starts_with('.') || host.parse::<IpAddr>().is_ok();
+    let path = if c.path.starts_with('/') {
+        c.path.as_str()
+    } else {
+        "/"
+    };
+    let mut raw = RawCookie::build((c.name.as_str(), c.value.as_str()))
+        .path(path)
+        .secure(c.secure)
+        .http_only(c.http_only);
+    if !host_only {
+        raw = raw.domain(host);
+    }
+    if let Some(e) = c.expires {
+        // browsers may store "never" as a time too far out to represent
+        let t = OffsetDateTime::from_unix_timestamp(e.min(MAX_EXPIRY) as i64)
+            .map_err(|e| err(e.into()))?;
+        raw = raw.expires(t);
+    }
+    // as stored by browsers
+    raw = match c.same_site {
+        0 => raw.same_site(SameSite::None),
+        1 => raw.same_site(SameSite::Lax),
+        2 => raw.same_site(SameSite::Strict),
+        _ => raw,
+    };
+    let url_host = if host.contains(':') {
+        format!("[{host}]")
+    } else {
+        host.to_string()
+    };
+    let url = Url::parse(&format!("https://{url_host}{path}")).map_err(|e| err(e.into()))?;
+    cs.insert_raw(&raw.build(), &url)
+        .map_err(|e| err(e.into()))?;
+    Ok(())
+}
+
+fn from_store(c: &StoredCookie) -> Cookie {
+    Cookie {
+        domain: match &c.domain {
+            CookieDomain::HostOnly(host) => host.clone(),
+            d => format!(".{}", String::from(d)),
+        },
+        path: String::from(&c.path),
+        secure: c.secure().unwrap_or(false),
+        expires: match c.expires {
+            CookieExpiration::AtUtc(t) => Some(t.unix_timestamp().max(0) as u64),
+            CookieExpiration::SessionEnd => None,
+        },
+        name: c.name().to_string(),
+        value: c.value().to_string(),
+        http_only: c.http_only().unwrap_or(false),
+        same_site: match c.same_site().map(|s| s.to_string()).as_deref() {
+            Some("None") => 0,
+            Some("Lax") => 1,
+            Some("Strict") => 2,
+            _ => -1,
+        },
+    }
+}
+
+/// Write the cookies of `store` to `path`, as JSON if it ends in `.json`,
+/// otherwise in the Netscape format. Expired cookies are dropped. Session
+/// cookies are only written with `keep_session`, in which case they outlive
+/// the session they belong to. Returns the number of cookies written.
+pub fn save_cookies(
+    store: &CookieStoreMutex,
+    path: &Path,
+    keep_session: bool,
+) -> Result<usize, Error> {
+    let cookies: Vec<_> = store
+        .lock()
+        .unwrap()
+        .iter_unexpired()
+        .filter(|c| keep_session || c.is_persistent())
+        .map(from_store)
+        .collect();
+    let text = match CookieSource::file(path) {
+        CookieSource::Json(_) => {
+            let cookies: Vec<_> = cookies.iter().map(JsonCookie::from).collect();
+            serde_json::to_string_pretty(&cookies).expect("cookies serialize")
+        }
+        _ => write_netscape(&cookies),
+    };
+    let temp = temp_path(path).ok_or_else(|| Error::NotAFile {
+        path: path.to_owned(),
+    })?;
+    write(&temp, text).map_err(Error::io("Could not write cookie file", &temp))?;
+    rename(&temp, path).map_err(Error::io("Could not write cookie file", path))?;
+    Ok(cookies.len())
+}
+
+fn write_netscape(cookies: &[Cookie]) -> String {
+    let mut out = String::from("# Netscape HTTP Cookie File\n");
+    for c in cookies {
+        let flag = |b| if b { "TRUE" } else { "FALSE" };
+        out.push_str(&format!(
+            "{}{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+            if c.http_only { "#HttpOnly_" } else { "" },
+            c.domain,
+            flag(c.domain.starts_with('.')),
+            c.path,
+            flag(c.secure),
+            c.expires.unwrap_or(0),
+            c.name,
+            c.value
+        ));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn netscape() {
+        let text = "# Netscape HTTP Cookie File\n\
+            \n\
+            example.com\tTRUE\t/\tFALSE\t0\ta\t1\n\
+            .example.org\tTRUE\t/x\tTRUE\t2000000000\tb\t2\n\
+            #HttpOnly_host.test\tFALSE\t/\tFALSE\t0\tc\t\n";
+        let c = parse_netscape(text, Path::new("c.txt")).unwrap();
+        assert_eq!(c.len(), 3);
+        assert_eq!(c[0].domain, ".example.com");
+        assert_eq!(c[0].expires, None);
+        assert!(!c[0].http_only);
+        assert_eq!(c[1].domain, ".example.org");
+        assert_eq!(c[1].path, "/x");
+        assert!(c[1].secure);
+        assert_eq!(c[1].expires, Some(2000000000));
+        assert_eq!((c[1].name.as_str(), c[1].value.as_str()), ("b", "2"));
+        assert_eq!(c[2].domain, "host.test");
+        assert!(c[2].http_only);
+        assert_eq!(c[2].value, "");
+    }
+
+    #[test]
+    fn netscape_errors() {
+        let e = parse_netscape("# x\na\tb\n", Path::new("c.txt")).unwrap_err();
+        assert!(matches!(e, Error::CookieFile { line: 2, .. }));
+        let e = parse_netscape("a\tFALSE\t/\tFALSE\tsoon\tn\tv\n", Path::new("c.txt"));
+        assert!(matches!(e, Err(Error::CookieFile { line: 1, .. })));
+    }
+
+    #[test]
+    fn json() {
+        let text = r#"[
+            {"domain": "example.com", "name": "a", "value": "1", "hostOnly": true,
+             "httpOnly": true, "expirationDate": 2000000000.5, "sameSite": "lax"},
+            {"domain": "example.org", "name": "b", "value": "2", "path": "/x",
+             "secure": true}
+        ]"#;
+        let c = parse_json(text, Path::new("c.json")).unwrap();
+        assert_eq!(c[0].domain, "example.com");
+        assert_eq!(c[0].path, "/");
+        assert!(c[0].http_only);
+        assert_eq!(c[0].expires, Some(2000000000));
+        assert_eq!(c[0].same_site, 1);
+        assert_eq!(c[1].domain, ".example.org");
+        assert_eq!(c[1].path, "/x");
+        assert!(c[1].secure);
+        assert_eq!(c[1].expires, None);
+        assert_eq!(c[1].same_site, -1);
+        assert!(parse_json("{}", Path::new("c.json")).is_err());
+    }
+
+    #[test]
+    fn store_clamps_far_expiry() {
+        let mut c = parse_header("example.com", "far=1; gone=2");
+        c[0].expires = Some(u64::MAX);
+        c[1].expires = Some(1);
+        let imported = store_cookies(c, true).unwrap();
+        let store = imported.store.lock().unwrap();
+        let names: Vec<_> = store.iter_any().map(|c| c.name()).collect();
+        assert_eq!(names, ["far"]);
+    }
+
+    #[test]
+    fn header() {
+        let c = parse_header("example.com", "Cookie: a=1; b = x=y ;junk; c=");
+        let pairs: Vec<_> = c.iter().map(|c| (&*c.name, &*c.value)).collect();
+        assert_eq!(pairs, [("a", "1"), ("b", "x=y"), ("c", "")]);
+        assert!(c.iter().all(|c| c.domain == "example.com" && c.path == "/"));
+    }
 }
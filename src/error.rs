@@ -0,0 +1,159 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use reqwest::StatusCode;
+
+use crate::{
+    checksum::Checksum,
+    cookies::Browser,
+    operation::OperationReport,
+    retry::{ErrorKind, DEFAULT_STATUSES},
+};
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(
+        "Error in {}HTTP request: HTTP status {status} for url ({url})",
+        if *preflight { "preflight " } else { "" }
+    )]
+    HttpStatus {
+        url: String,
+        status: StatusCode,
+        retry_after: Option<Duration>,
+        preflight: bool,
+    },
+    #[error("Error sending HTTP request to '{url}': {source}")]
+    Request { url: String, source: reqwest::Error },
+    #[error("Error streaming bytes from '{url}': {source}")]
+    Stream { url: String, source: reqwest::Error },
+    #[error("Invalid content-length header from '{url}': {value:?}")]
+    InvalidContentLength { url: String, value: String },
+    #[error("Unexpected content-range in response to resume of '{url}' from {offset}")]
+    ContentRange { url: String, offset: u64 },
+    #[error("Segment {start}-{end} of '{url}' {reason}")]
+    Segment {
+        url: String,
+        start: u64,
+        end: u64,
+        reason: &'static str,
+    },
+    #[error("Invalid content-disposition header '{header}': {reason}")]
+    Disposition {
+        header: String,
+        reason: &'static str,
+    },
+    #[error("Could not determine filename for '{url}': {reason}")]
+    Filename { url: String, reason: &'static str },
+    #[error("File '{}' already exists. failing!", path.display())]
+    FileExists { path: PathBuf },
+    #[error("File exists and is not a regular file: '{}'", path.display())]
+    NotAFile { path: PathBuf },
+    #[error("{context} '{}': {source}", path.display())]
+    Io {
+        context: &'static str,
+        path: PathBuf,
+        source: io::Error,
+    },
+    #[error("Checksum mismatch for '{}': expected {expected}, got {actual}", path.display())]
+    ChecksumMismatch {
+        path: PathBuf,
+        expected: Checksum,
+        actual: String,
+    },
+    #[error("Invalid checksum '{value}': {reason}")]
+    InvalidChecksum { value: String, reason: &'static str },
+    #[error("Could not build HTTP client: {0}")]
+    Client(#[source] reqwest::Error),
+    #[error("Could not read cookies from {browser}: {source}")]
+    Browser { browser: Browser, source: BoxError },
+    #[error("Could not import cookie {cookie}: {source}")]
+    Cookie { cookie: String, source: BoxError },
+    #[error("Invalid cookie file '{}' at line {line}: {reason}", path.display())]
+    CookieFile {
+        path: PathBuf,
+        line: usize,
+        reason: String,
+    },
+    #[error("Invalid manifest at line {line}: {reason}")]
+    Manifest { line: usize, reason: String },
+    #[error("Operation was shut down")]
+    Closed,
+    #[error("Download was cancelled")]
+    Cancelled,
+    #[error("Download task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+    #[error("{} of {} downloads failed", .0.failed(), .0.total())]
+    Incomplete(Box<OperationReport>),
+}
+
+impl Error {
+    /// For use with `map_err`: wrap an IO error with what we were doing and to
+    /// which file.
+    pub(crate) fn io<'a>(
+        context: &'static str,
+        path: &'a Path,
+    ) -> impl FnOnce(io::Error) -> Self + 'a {
+        move |source| Self::Io {
+            context,
+            path: path.to_owned(),
+            source,
+        }
+    }
+    pub fn is_http_status(&self) -> bool {
+        matches!(self, Self::HttpStatus { .. })
+    }
+    /// Whether this failure is likely to go away on its own: a connection or
+    /// timeout problem, or one of the statuses retried by default (408, 429,
+    /// 500, 502, 503, 504).
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::HttpStatus { status, .. } => DEFAULT_STATUSES.contains(status),
+            _ => self.transport_kind().is_some(),
+        }
+    }
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            Self::HttpStatus { status, .. } => Some(*status),
+            _ => None,
+        }
+    }
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::HttpStatus { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+    pub fn transport_kind(&self) -> Option<ErrorKind> {
+        match self {
+            Self::Request { source, .. } | Self::Stream { source, .. } => ErrorKind::of(source),
+            _ => None,
+        }
+    }
+    pub fn url(&self) -> Option<&str> {
+        match self {
+            Self::HttpStatus { url, .. }
+            | Self::Request { url, .. }
+            | Self::Stream { url, .. }
+            | Self::InvalidContentLength { url, .. }
+            | Self::ContentRange { url, .. }
+            | Self::Segment { url, .. }
+            | Self::Filename { url, .. } => Some(url),
+            _ => None,
+        }
+    }
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Self::FileExists { path }
+            | Self::NotAFile { path }
+            | Self::Io { path, .. }
+            | Self::CookieFile { path, .. }
+            | Self::ChecksumMismatch { path, .. } => Some(path),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::http::{FileDownload, Outcome};
+use crate::operation::DownloadReport;
+use crate::reporter::Reporter;
+use crate::Error;
+
+/// Something that happened to a download during `Operation::run`.
+///
+/// `id` numbers the downloads of a run in the order the source yielded them.
+#[derive(Debug, Clone)]
+pub enum DownloadEvent {
+    Queued {
+        id: usize,
+        url: String,
+    },
+    Started {
+        id: usize,
+        url: String,
+    },
+    /// Response headers arrived. Sent again for each retry.
+    Response {
+        id: usize,
+        path: PathBuf,
+        len: Option<u64>,
+    },
+    /// Sent for every chunk received.
+    Progress {
+        id: usize,
+        len: Option<u64>,
+        pos: u64,
+    },
+    Retried {
+        id: usize,
+        attempt: u32,
+        error: String,
+        delay: Duration,
+    },
+    /// The file already existed and was left alone.
+    Skipped {
+        id: usize,
+        url: String,
+        path: PathBuf,
+    },
+    Completed {
+        id: usize,
+        url: String,
+        path: PathBuf,
+        outcome: Outcome,
+        bytes: u64,
+    },
+    Failed {
+        id: usize,
+        url: String,
+        error: String,
+        attempts: u32,
+    },
+}
+
+impl DownloadEvent {
+    pub fn id(&self) -> usize {
+        match self {
+            Self::Queued { id, .. }
+            | Self::Started { id, .. }
+            | Self::Response { id, .. }
+            | Self::Progress { id, .. }
+            | Self::Retried { id, .. }
+            | Self::Skipped { id, .. }
+            | Self::Completed { id, .. }
+            | Self::Failed { id, .. } => *id,
+        }
+    }
+}
+
+/// Called with every event of a run, from the download tasks.
+pub type Observer = Arc<dyn Fn(&DownloadEvent) + Send + Sync>;
+
+/// An observer that sends events down a channel, ignoring a closed receiver.
+pub fn channel_observer(tx: UnboundedSender<DownloadEvent>) -> Observer {
+    Arc::new(move |e| {
+        let _ = tx.send(e.clone());
+    })
+}
+
+pub(crate) struct EventReporter(pub(crate) Vec<Observer>);
+
+impl EventReporter {
+    fn emit(&self, event: DownloadEvent) {
+        self.0.iter().for_each(|o| o(&event));
+    }
+}
+
+impl Reporter for EventReporter {
+    fn queued(&self, id: usize, download: &FileDownload) {
+        self.emit(DownloadEvent::Queued {
+            id,
+            url: download.url.clone(),
+        });
+    }
+    fn started(&self, id: usize, download: &FileDownload) {
+        self.emit(DownloadEvent::Started {
+            id,
+            url: download.url.clone(),
+        });
+    }
+    fn response(&self, id: usize, path: &Path, len: Option<u64>) {
+        self.emit(DownloadEvent::Response {
+            id,
+            path: path.to_owned(),
+            len,
+        });
+    }
+    fn progress(&self, id: usize, len: Option<u64>, pos: u64) {
+        self.emit(DownloadEvent::Progress { id, len, pos });
+    }
+    fn retrying(&self, id: usize, attempt: u32, error: &Error, delay: Duration) {
+        self.emit(DownloadEvent::Retried {
+            id,
+            attempt,
+            error: error.to_string(),
+            delay,
+        });
+    }
+    fn finished(&self, id: usize, report: &DownloadReport) {
+        let url = report.download.url.clone();
+        self.emit(match &report.result {
+            Ok((path, Outcome::Existing)) => DownloadEvent::Skipped {
+                id,
+                url,
+                path: path.clone(),
+            },
+            Ok((path, outcome)) => DownloadEvent::Completed {
+                id,
+                url,
+                path: path.clone(),
+                outcome: *outcome,
+                bytes: report.bytes,
+            },
+            Err(e) => DownloadEvent::Failed {
+                id,
+                url,
+                error: e.to_string(),
+                attempts: report.attempts,
+            },
+        });
+    }
+}
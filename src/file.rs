@@ -1,16 +1,17 @@
 use std::{
-    error::Error,
     ffi::{OsStr, OsString},
+    io::SeekFrom,
     path::{Path, PathBuf},
 };
 
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
 use tokio::{
-    fs::{remove_file, rename, File},
-    io::AsyncWriteExt,
-    spawn,
+    fs::{read_to_string, remove_file, rename, write, File},
+    io::{AsyncSeekExt, AsyncWriteExt},
 };
 
+use crate::Error;
+
 pub fn temp_path(p: &Path) -> Option<PathBuf> {
     let o = temp_filename(p.file_name()?);
     Some(p.parent().map_or_else(|| PathBuf::from(&o), |i| i.join(&o)))
@@ -26,61 +27,256 @@ pub fn temp_filename(filename: &OsStr) -> OsString {
         .collect()
 }
 
+/// A hidden file alongside `p`: `dir/.name.ext`.
+pub fn hidden_path(p: &Path, ext: &str) -> Option<PathBuf> {
+    let period = OsStr::new(".");
+    let o: OsString = vec![period, p.file_name()?, period, OsStr::new(ext)]
+        .into_iter()
+        .collect();
+    Some(p.parent().map_or_else(|| PathBuf::from(&o), |i| i.join(&o)))
+}
+
+/// Deterministic temp path used for resumable downloads, so a later run can
+/// find the partial file again: `dir/.name.part`.
+pub fn partial_path(p: &Path) -> Option<PathBuf> {
+    hidden_path(p, "part")
+}
+
+/// Sidecar holding cache validators for a downloaded file: `dir/.name.meta`.
+pub fn meta_path(p: &Path) -> Option<PathBuf> {
+    hidden_path(p, "meta")
+}
+
+fn validator_path(partial: &Path) -> PathBuf {
+    let mut o = partial.as_os_str().to_owned();
+    o.push(".validator");
+    PathBuf::from(o)
+}
+
 pub struct AtomicFile {
     file: File,
     temp_path: PathBuf,
     target_path: PathBuf,
     committed: bool,
+    // keep the temp file on drop so it can be resumed later
+    resumable: bool,
 }
 
 impl AtomicFile {
-    pub async fn open<P>(p: P) -> Result<Self, Box<dyn Error>>
+    pub async fn open<P>(p: P) -> Result<Self, Error>
     where
         P: AsRef<Path>,
     {
         let target_path = p.as_ref().to_owned();
-        let temp_path = temp_path(&target_path).ok_or("Should be a regular file")?;
+        let temp_path = temp_path(&target_path).ok_or_else(|| Error::NotAFile {
+            path: target_path.clone(),
+        })?;
         let file = File::options()
             .create_new(true)
             .write(true)
             .open(&temp_path)
-            .await?;
+            .await
+            .map_err(Error::io("Could not open tempfile for writing", &temp_path))?;
         Ok(AtomicFile {
             file,
             temp_path,
             target_path,
             committed: false,
+            resumable: false,
         })
     }
-    pub async fn write_all(&mut self, data: &[u8]) -> Result<(), Box<dyn Error>> {
-        Ok(self.file.write_all(data).await?)
+    /// Open (or reopen) the partial file for `p`.
+    ///
+    /// If a partial file exists and was started against the same `validator`
+    /// (an ETag or Last-Modified value) it is opened positioned at its end and
+    /// the number of bytes already present is returned. Otherwise any partial file
+    /// is truncated and `validator` is recorded for a future resume.
+    pub async fn resume<P>(p: P, validator: Option<&str>) -> Result<(Self, u64), Error>
+    where
+        P: AsRef<Path>,
+    {
+        let target_path = p.as_ref().to_owned();
+        let temp_path = partial_path(&target_path).ok_or_else(|| Error::NotAFile {
+            path: target_path.clone(),
+        })?;
+        let vpath = validator_path(&temp_path);
+        let stored = read_to_string(&vpath).await.ok();
+        let reusable = validator.is_some() && stored.as_deref() == validator;
+        // not in append mode, which would ignore the seeks of `write_at`
+        let mut file = File::options()
+            .create(true)
+            .write(true)
+            .truncate(!reusable)
+            .open(&temp_path)
+            .await
+            .map_err(Error::io(
+                "Could not open partial file for writing",
+                &temp_path,
+            ))?;
+        let offset = if reusable {
+            file.seek(SeekFrom::End(0))
+                .await
+                .map_err(Error::io("Error seeking in tempfile", &temp_path))?
+        } else {
+            match validator {
+                Some(v) => write(&vpath, v)
+                    .await
+                    .map_err(Error::io("Could not write validator", &vpath))?,
+                None => {
+                    let _ = remove_file(&vpath).await;
+                }
+            }
+            0
+        };
+        Ok((
+            AtomicFile {
+                file,
+                temp_path,
+                target_path,
+                committed: false,
+                resumable: true,
+            },
+            offset,
+        ))
+    }
+    /// Throw away anything written so far, e.g. when the server ignored a
+    /// range request and is sending the whole body again.
+    pub async fn truncate(&mut self) -> Result<(), Error> {
+        self.file
+            .set_len(0)
+            .await
+            .map_err(Error::io("Could not truncate", &self.temp_path))?;
+        self.file
+            .rewind()
+            .await
+            .map_err(Error::io("Could not truncate", &self.temp_path))?;
+        Ok(())
     }
-    pub async fn commit(&mut self) -> Result<(), Box<dyn Error>> {
+    /// Size the file to `len` up front, to write segments at their offsets.
+    pub async fn allocate(&mut self, len: u64) -> Result<(), Error> {
+        self.file
+            .set_len(len)
+            .await
+            .map_err(Error::io("Could not allocate", &self.temp_path))
+    }
+    pub fn temp_path(&self) -> &Path {
+        &self.temp_path
+    }
+    pub async fn write_all(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.file.write_all(data).await.map_err(Error::io(
+            "Error writing bytes to tempfile",
+            &self.temp_path,
+        ))
+    }
+    pub async fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<(), Error> {
+        self.file
+            .seek(SeekFrom::Start(offset))
+            .await
+            .map_err(Error::io("Error seeking in tempfile", &self.temp_path))?;
+        self.write_all(data).await
+    }
+    /// Make everything written so far visible to other readers of the file.
+    pub async fn flush(&mut self) -> Result<(), Error> {
+        self.file
+            .flush()
+            .await
+            .map_err(Error::io("Error flushing tempfile", &self.temp_path))
+    }
+    pub async fn commit(&mut self) -> Result<(), Error> {
         if self.committed {
             return Ok(());
         }
         self.committed = true;
-        self.file.sync_all().await?;
-        rename(&self.temp_path, &self.target_path).await?;
+        self.file
+            .sync_all()
+            .await
+            .map_err(Error::io("Error committing written file", &self.temp_path))?;
+        rename(&self.temp_path, &self.target_path)
+            .await
+            .map_err(Error::io(
+                "Error committing written file",
+                &self.target_path,
+            ))?;
+        if self.resumable {
+            let _ = remove_file(validator_path(&self.temp_path)).await;
+        }
         Ok(())
     }
-    pub async fn discard(&mut self) -> Result<(), Box<dyn Error>> {
+    pub async fn discard(&mut self) -> Result<(), Error> {
         if self.committed {
             return Ok(());
         }
-        Ok(remove_file(&self.target_path).await?)
+        self.committed = true;
+        if self.resumable {
+            let _ = remove_file(validator_path(&self.temp_path)).await;
+        }
+        remove_file(&self.temp_path)
+            .await
+            .map_err(Error::io("Could not remove tempfile", &self.temp_path))
     }
 }
 
 impl Drop for AtomicFile {
     fn drop(&mut self) {
-        if self.committed {
+        if self.committed || self.resumable {
             return;
         }
-        let p = self.temp_path.clone();
-        // TODO: should we just do this sync as this only happens in an error condition?!
-        spawn(async move {
-            let _ = remove_file(&p).await;
-        });
+        // synchronously, as a spawned task may never run during shutdown
+        let _ = std::fs::remove_file(&self.temp_path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn resume_reuses_matching_partial() {
+        let dir = tempdir().unwrap();
+        let target = dir.path().join("f");
+        let (mut f, offset) = AtomicFile::resume(&target, Some("v1")).await.unwrap();
+        assert_eq!(offset, 0);
+        f.write_all(b"AAAA").await.unwrap();
+        f.flush().await.unwrap();
+        drop(f);
+
+        let (mut f, offset) = AtomicFile::resume(&target, Some("v1")).await.unwrap();
+        assert_eq!(offset, 4);
+        f.write_all(b"BBBB").await.unwrap();
+        f.commit().await.unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), b"AAAABBBB");
+        assert!(!validator_path(&partial_path(&target).unwrap()).exists());
+    }
+
+    #[tokio::test]
+    async fn resume_restarts_on_other_validator() {
+        let dir = tempdir().unwrap();
+        let target = dir.path().join("f");
+        let (mut f, _) = AtomicFile::resume(&target, Some("v1")).await.unwrap();
+        f.write_all(b"AAAA").await.unwrap();
+        drop(f);
+        let (mut f, offset) = AtomicFile::resume(&target, Some("v2")).await.unwrap();
+        assert_eq!(offset, 0);
+        f.write_all(b"CC").await.unwrap();
+        f.commit().await.unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), b"CC");
+    }
+
+    #[tokio::test]
+    async fn write_at_after_resume() {
+        let dir = tempdir().unwrap();
+        let target = dir.path().join("f");
+        let (mut f, _) = AtomicFile::resume(&target, Some("v1")).await.unwrap();
+        f.truncate().await.unwrap();
+        drop(f);
+        let (mut f, offset) = AtomicFile::resume(&target, Some("v1")).await.unwrap();
+        assert_eq!(offset, 0);
+        f.allocate(8).await.unwrap();
+        f.write_at(4, b"BBBB").await.unwrap();
+        f.write_at(0, b"AAAA").await.unwrap();
+        f.commit().await.unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), b"AAAABBBB");
     }
 }
@@ -1,14 +1,45 @@
-use std::{borrow::Cow, error::Error, path::PathBuf, str::from_utf8, sync::Arc};
+use std::{
+    borrow::Cow,
+    path::{Path, PathBuf},
+    str::from_utf8,
+    sync::Arc,
+    time::Duration,
+};
 
+use clap::ValueEnum;
 use derive_builder::Builder;
-use futures_util::StreamExt as _;
+use futures_util::{
+    future::{ready, try_join_all, Either},
+    stream::{select_all, unfold},
+    StreamExt as _,
+};
 use mailparse::DispositionType;
 use percent_encoding::percent_decode_str;
-use reqwest::{Client, Method, Response};
+use reqwest::{
+    header::{
+        HeaderMap, ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, ETAG, IF_MODIFIED_SINCE,
+        IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED, RANGE,
+    },
+    Client, Method, RequestBuilder, Response, StatusCode, Url,
+};
 use reqwest_cookie_store::CookieStoreMutex;
-use tokio::fs::create_dir_all;
+use serde::{Deserialize, Serialize};
+use strum::IntoStaticStr;
+use tokio::{
+    fs::{create_dir_all, metadata, read_to_string, remove_file, write, File},
+    time::sleep,
+};
 
-pub fn get_client(cs: Option<Arc<CookieStoreMutex>>) -> Result<Client, Box<dyn Error>> {
+use crate::{
+    checksum::Checksum,
+    control::PauseGate,
+    file::{meta_path, AtomicFile},
+    ratelimit::RateLimiter,
+    retry::{retry_after, RetryPolicy},
+    Error,
+};
+
+pub fn get_client(cs: Option<Arc<CookieStoreMutex>>) -> Result<Client, Error> {
     let mut cb = Client::builder()
         .user_agent(
             "Mozilla/5.0 (Macintosh; Intel Mac OS X 10.15; rv:122.0) Gecko/20100101 Firefox/122.0",
@@ -19,10 +50,37 @@ pub fn get_client(cs: Option<Arc<CookieStoreMutex>>) -> Result<Client, Box<dyn E
         Some(v) => cb.cookie_provider(v),
         None => cb.cookie_store(true),
     };
-    Ok(cb.build()?)
+    cb.build().map_err(Error::Client)
+}
+
+/// Make a server-provided name safe to use as a single path component:
+/// anything before a path separator is dropped, control and reserved
+/// characters are replaced, and empty, `.` and `..` names are rejected.
+pub fn sanitize_filename(name: &str) -> Option<String> {
+    let name = name.rsplit(['/', '\\']).next()?;
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let name = name.trim().trim_end_matches('.');
+    if name.is_empty() || name == "." || name == ".." {
+        None
+    } else {
+        Some(name.to_string())
+    }
 }
 
-pub fn filename_from_disposition(cd: &str) -> Result<String, Box<dyn Error>> {
+/// The percent-decoded, sanitized last path segment of `url`.
+pub fn filename_from_url(url: &Url) -> Option<String> {
+    let segment = url.path_segments()?.next_back()?;
+    sanitize_filename(&percent_decode_str(segment).decode_utf8().ok()?)
+}
+
+pub fn filename_from_disposition(cd: &str) -> Result<String, Error> {
     let x = mailparse::parse_content_disposition(cd);
     if let DispositionType::Attachment = x.disposition {
         Ok(x.params
@@ -34,29 +92,187 @@ pub fn filename_from_disposition(cd: &str) -> Result<String, Box<dyn Error>> {
                     .get("filename")
                     .and_then(|i| percent_decode_str(i).decode_utf8().ok())
             })
-            .ok_or_else(|| {
-                format!("Could not parse a filename from the content-disposition header '{cd}'")
-            })?
-            .to_string())
+            .and_then(|i| sanitize_filename(&i))
+            .ok_or_else(|| Error::Disposition {
+                header: cd.to_string(),
+                reason: "could not parse a filename",
+            })?)
     } else {
-        Err(format!(
-            "Content-disposition is expected to be an attachment with filename param. got '{cd}'"
-        )
-        .into())
+        Err(Error::Disposition {
+            header: cd.to_string(),
+            reason: "expected an attachment with filename param",
+        })
+    }
+}
+
+pub(crate) fn check_status(r: Response, preflight: bool) -> Result<Response, Error> {
+    if r.status().is_client_error() || r.status().is_server_error() {
+        Err(Error::HttpStatus {
+            url: r.url().to_string(),
+            status: r.status(),
+            retry_after: retry_after(r.headers()),
+            preflight,
+        })
+    } else {
+        Ok(r)
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+/// A validator suitable for `If-Range`: a strong ETag, falling back to
+/// Last-Modified. Weak ETags cannot be used for range requests.
+fn range_validator(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(ETAG)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.starts_with("W/"))
+        .or_else(|| headers.get(LAST_MODIFIED).and_then(|v| v.to_str().ok()))
+        .map(|v| v.to_string())
+}
+
+/// Segmented downloads use no segments smaller than this.
+pub const MIN_SEGMENT_SIZE: u64 = 1024 * 1024;
+
+/// Start offset of a `Content-Range: bytes start-end/len` header.
+fn content_range_start(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(CONTENT_RANGE)?
+        .to_str()
+        .ok()?
+        .strip_prefix("bytes ")?
+        .split_once('-')?
+        .0
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Cache validators recorded for `OverwriteBehaviour::CheckFreshness`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let get = |h| {
+            headers
+                .get(h)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string())
+        };
+        Self {
+            etag: get(ETAG),
+            last_modified: get(LAST_MODIFIED),
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+    /// Whether both describe the same version, preferring the ETag.
+    pub fn matches(&self, other: &Self) -> bool {
+        match (&self.etag, &other.etag) {
+            (Some(a), Some(b)) => a == b,
+            _ => {
+                matches!((&self.last_modified, &other.last_modified), (Some(a), Some(b)) if a == b)
+            }
+        }
+    }
+    /// Validators for an existing download: from its sidecar if present,
+    /// otherwise its mtime.
+    pub async fn for_file(path: &Path) -> Option<Self> {
+        if let Some(text) = match meta_path(path) {
+            Some(m) => read_to_string(m).await.ok(),
+            None => None,
+        } {
+            let mut v = Self::default();
+            for (k, val) in text.lines().filter_map(|l| l.split_once(": ")) {
+                match k {
+                    "ETag" => v.etag = Some(val.to_string()),
+                    "Last-Modified" => v.last_modified = Some(val.to_string()),
+                    _ => (),
+                }
+            }
+            return Some(v);
+        }
+        let mtime = metadata(path).await.ok()?.modified().ok()?;
+        Some(Self {
+            etag: None,
+            last_modified: Some(httpdate::fmt_http_date(mtime)),
+        })
+    }
+    /// Record these validators for `path` and set its mtime from Last-Modified.
+    pub async fn save(&self, path: &Path) -> Result<(), Error> {
+        let meta = meta_path(path).ok_or_else(|| Error::NotAFile {
+            path: path.to_owned(),
+        })?;
+        if self.is_empty() {
+            let _ = remove_file(&meta).await;
+        } else {
+            let mut text = String::new();
+            if let Some(e) = &self.etag {
+                text.push_str(&format!("ETag: {e}\n"));
+            }
+            if let Some(l) = &self.last_modified {
+                text.push_str(&format!("Last-Modified: {l}\n"));
+            }
+            write(&meta, text)
+                .await
+                .map_err(Error::io("Could not write metadata", &meta))?;
+        }
+        if let Some(t) = self
+            .last_modified
+            .as_deref()
+            .and_then(|v| httpdate::parse_http_date(v).ok())
+        {
+            File::options()
+                .write(true)
+                .open(path)
+                .await
+                .map_err(Error::io("Could not open", path))?
+                .into_std()
+                .await
+                .set_modified(t)
+                .map_err(Error::io("Could not set modification time of", path))?;
+        }
+        Ok(())
+    }
+    fn apply(&self, mut req: RequestBuilder) -> RequestBuilder {
+        if let Some(e) = &self.etag {
+            req = req.header(IF_NONE_MATCH, e);
+        }
+        if let Some(l) = &self.last_modified {
+            req = req.header(IF_MODIFIED_SINCE, l);
+        }
+        req
+    }
+}
+
+/// What `FileDownload::download` did. Lengths are the size of the file written.
+#[derive(Debug, Clone, Copy, IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
 pub enum Outcome {
     Download(u64),
     Redownload(u64),
+    /// Continued a partial download: (bytes reused, total length).
+    Resumed(u64, u64),
     Existing,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
 pub enum OverwriteBehaviour {
     Always,
+    /// Re-download if the existing file's size differs from the content-length.
+    /// When the server does not send a content-length the file is re-downloaded.
     CheckLength,
+    /// Re-download unless the existing file matches the download's checksum.
+    /// Without a checksum the file is re-downloaded.
+    CheckHash,
+    /// Send a conditional request using the ETag/Last-Modified recorded in a
+    /// `.name.meta` sidecar (or the file's mtime) and keep the file on a 304.
+    /// Downloaded files get their mtime set from Last-Modified.
+    CheckFreshness,
     #[default]
     Never,
     Fail,
@@ -65,11 +281,12 @@ pub enum OverwriteBehaviour {
 impl OverwriteBehaviour {
     #[inline]
     fn conditional(&self) -> bool {
-        matches!(self, Self::CheckLength)
+        matches!(self, Self::CheckLength | Self::CheckFreshness)
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
 pub enum UsagePref {
     Require,
     Prefer, // if available
@@ -88,7 +305,8 @@ impl UsagePref {
     }
 }
 
-#[derive(Debug, Clone, Builder)]
+/// Serializes its own settings only; retry policy and rate limits are skipped.
+#[derive(Debug, Clone, Builder, Serialize, Deserialize)]
 pub struct FileDownload {
     // #[builder(setter(into))]
     // client: Client,
@@ -97,17 +315,60 @@ pub struct FileDownload {
     #[builder(setter(into))]
     pub url: String,
     #[builder(setter(into))]
-    target: PathBuf,
+    #[serde(default)]
+    pub(crate) target: PathBuf,
     #[builder(default)]
+    #[serde(default)]
     preflight_head: bool,
     #[builder(default)]
+    #[serde(default)]
     overwrite: OverwriteBehaviour,
+    // filenames are taken from the first available of: content-disposition,
+    // the final (post-redirect) URL, then `filename`. `target` is then the
+    // directory to download into.
     #[builder(default)]
+    #[serde(default)]
     filename_use_content_disposition: UsagePref,
     #[builder(default)]
+    #[serde(default)]
     filename_use_final_url: UsagePref,
     #[builder(default, setter(into))]
-    filename: Option<String>,
+    pub(crate) filename: Option<String>,
+    /// Keep partial downloads on disk and continue them with a range request.
+    /// Only possible when the server sends a content-length.
+    #[builder(default)]
+    #[serde(default)]
+    pub(crate) resume: bool,
+    /// Fetch the file over up to this many connections at once, when the
+    /// server accepts range requests and sends a content-length. Values below
+    /// 2 disable it, as does continuing a partial download.
+    #[builder(default)]
+    #[serde(default)]
+    segments: usize,
+    #[builder(default, setter(into, strip_option))]
+    #[serde(skip)]
+    pub(crate) retry: Option<RetryPolicy>,
+    /// Verified while streaming; on mismatch the file is discarded.
+    #[builder(default, setter(into, strip_option))]
+    checksum: Option<Checksum>,
+    #[builder(default, setter(custom))]
+    #[serde(skip)]
+    pub(crate) rate_limits: Vec<RateLimiter>,
+    #[builder(setter(skip))]
+    #[serde(skip)]
+    pub(crate) pause: Option<PauseGate>,
+}
+
+impl FileDownloadBuilder {
+    /// Throttle this download with `limiter`. May be called more than once,
+    /// e.g. for a per-download and a shared limit.
+    pub fn rate_limit(&mut self, limiter: RateLimiter) -> &mut Self {
+        self.rate_limits.get_or_insert_with(Vec::new).push(limiter);
+        self
+    }
+    pub(crate) fn target_dir(&self) -> Option<&PathBuf> {
+        self.target.as_ref()
+    }
 }
 
 impl FileDownload {
@@ -138,133 +399,648 @@ impl FileDownload {
     fn should_preflight(&self) -> bool {
         self.preflight_head && (self.expect_filename() || self.overwrite.conditional())
     }
-    fn filename(&self, resp: &Response) -> Result<Option<String>, Box<dyn Error>> {
+    async fn matches_checksum(&self, path: &Path) -> Result<bool, Error> {
+        match &self.checksum {
+            Some(c) => c.verify_file(path).await,
+            None => Ok(false),
+        }
+    }
+    fn content_length(&self, resp: &Response) -> Result<Option<u64>, Error> {
+        resp.headers()
+            .get(CONTENT_LENGTH)
+            .map(|h| {
+                h.to_str().ok().and_then(|v| v.parse().ok()).ok_or_else(|| {
+                    Error::InvalidContentLength {
+                        url: self.url.clone(),
+                        value: String::from_utf8_lossy(h.as_bytes()).into_owned(),
+                    }
+                })
+            })
+            .transpose()
+    }
+    fn filename(&self, resp: &Response) -> Result<Option<String>, Error> {
         if self.filename_use_content_disposition.bool() {
             if let Some(disposition_header) = resp.headers().get("Content-disposition") {
                 let disposition = disposition_header.to_str().or_else(|_| {
-                    from_utf8(disposition_header.as_bytes())
-                        .map_err(|e| format!("Could not decode disposition header from UTF8: {e}"))
+                    from_utf8(disposition_header.as_bytes()).map_err(|_| Error::Disposition {
+                        header: String::from_utf8_lossy(disposition_header.as_bytes()).into_owned(),
+                        reason: "could not decode header from UTF8",
+                    })
                 })?;
                 return Ok(Some(filename_from_disposition(disposition)?));
             } else if self.filename_use_content_disposition.strict() {
-                return Err("No content-disposition header".into());
+                return Err(Error::Filename {
+                    url: self.url.clone(),
+                    reason: "no content-disposition header",
+                });
             }
         }
         if self.filename_use_final_url.bool() {
-            unimplemented!()
+            if let Some(f) = filename_from_url(resp.url()) {
+                return Ok(Some(f));
+            } else if self.filename_use_final_url.strict() {
+                return Err(Error::Filename {
+                    url: resp.url().to_string(),
+                    reason: "no usable path segment in final URL",
+                });
+            }
         }
         if let Some(f) = &self.filename {
             return Ok(Some(f.to_string()));
         } else if self.expect_filename() {
-            return Err("filename required but no default provided".into());
+            return Err(Error::Filename {
+                url: self.url.clone(),
+                reason: "filename required but no default provided",
+            });
         }
         Ok(None)
     }
-    pub async fn download<'a, F>(
-        &'a self,
+    /// How many segments to fetch the body of `r` in, if more than one.
+    fn segment_count(&self, r: &Response, len: Option<u64>) -> Option<usize> {
+        let ranges = r.headers().get(ACCEPT_RANGES)?.to_str().ok()?;
+        if self.segments < 2 || !ranges.eq_ignore_ascii_case("bytes") {
+            return None;
+        }
+        let n = (len? / MIN_SEGMENT_SIZE).min(self.segments as u64) as usize;
+        (n > 1).then_some(n)
+    }
+    async fn request_range(
+        &self,
+        client: &Client,
+        start: u64,
+        end: u64,
+        validator: Option<&str>,
+    ) -> Result<Response, Error> {
+        let mut req = client
+            .get(&self.url)
+            .header(RANGE, format!("bytes={start}-{}", end - 1));
+        if let Some(v) = validator {
+            req = req.header(IF_RANGE, v);
+        }
+        let r = req.send().await.map_err(|source| Error::Request {
+            url: self.url.clone(),
+            source,
+        })?;
+        let r = check_status(r, false)?;
+        if r.status() != StatusCode::PARTIAL_CONTENT
+            || content_range_start(r.headers()) != Some(start)
+        {
+            return Err(Error::Segment {
+                url: self.url.clone(),
+                start,
+                end,
+                reason: "was not served as a range",
+            });
+        }
+        Ok(r)
+    }
+    /// Fetch `len` bytes as `n` ranges in parallel, writing each at its
+    /// offset in `f`. The body of `first`, a full GET, serves the first range.
+    #[allow(clippy::too_many_arguments)]
+    async fn download_segments<F>(
+        &self,
         client: &Client,
+        mut first: Option<Response>,
+        len: u64,
+        n: usize,
+        validator: Option<&str>,
+        f: &mut AtomicFile,
         mut progress_cb: Option<F>,
-    ) -> Result<(PathBuf, Outcome), Box<dyn Error>>
+    ) -> Result<u64, Error>
     where
-        F: FnMut(u64, u64),
+        F: FnMut(Option<u64>, u64),
     {
-        let preflight = self.should_preflight();
-        let r = client
-            .request(
-                if preflight { Method::HEAD } else { Method::GET },
-                &self.url,
-            )
-            .send()
-            .await?
-            // TODO: fallback to GET if we get a 405 Method Not Allowed?
-            .error_for_status()
-            .map_err(|e| {
-                format!(
-                    "Error in {}HTTP request: {e}",
-                    if preflight { "preflight " } else { "" },
-                )
+        log::debug!("Downloading '{}' in {n} segments", self.url);
+        let size = len.div_ceil(n as u64);
+        let ranges: Vec<_> = (0..n as u64)
+            .map(|i| (i * size, ((i + 1) * size).min(len)))
+            .collect();
+        let responses = try_join_all(ranges.iter().map(|&(start, end)| match first.take() {
+            Some(r) => Either::Left(ready(Ok(r))),
+            None => Either::Right(self.request_range(client, start, end, validator)),
+        }))
+        .await?;
+        f.allocate(len).await?;
+        // each body, cut off at the end of its range, tagged with its index
+        let mut chunks = select_all(responses.into_iter().zip(&ranges).enumerate().map(
+            |(i, (r, (start, end)))| {
+                Box::pin(unfold(
+                    (r.bytes_stream(), end - start),
+                    move |(mut s, left)| async move {
+                        if left == 0 {
+                            return None;
+                        }
+                        match s.next().await? {
+                            Ok(mut b) => {
+                                b.truncate(left.min(b.len() as u64) as usize);
+                                let left = left - b.len() as u64;
+                                Some((Ok((i, b)), (s, left)))
+                            }
+                            Err(e) => Some((Err(e), (s, 0))),
+                        }
+                    },
+                ))
+            },
+        ));
+        let mut remaining: Vec<_> = ranges.iter().map(|(start, end)| end - start).collect();
+        let mut bytes = 0;
+        if let Some(f) = progress_cb.as_mut() {
+            f(Some(len), 0);
+        }
+        loop {
+            if let Some(p) = &self.pause {
+                p.wait().await;
+            }
+            let Some(v) = chunks.next().await else {
+                break;
+            };
+            let (i, b) = v.map_err(|source| Error::Stream {
+                url: self.url.clone(),
+                source,
             })?;
-        let len: u64 = r
-            .headers()
-            .get("Content-length")
-            //.ok_or("No content-length header")?
-            .ok_or_else(|| {
-                format!(
-                    "No content-length header. headers: {:?}",
-                    r.headers()
-                        .keys()
-                        .map(|e| e.to_string())
-                        .collect::<Vec<_>>()
-                        .join(", ")
-                )
-            })?
-            .to_str()?
-            .parse()?;
-        let filename: Cow<'_, PathBuf> = self.filename(&r)?.map_or_else(
-            || Cow::Borrowed(&self.target),
+            for l in &self.rate_limits {
+                l.acquire(b.len() as u64).await;
+            }
+            f.write_at(ranges[i].1 - remaining[i], &b).await?;
+            remaining[i] -= b.len() as u64;
+            bytes += b.len() as u64;
+            if let Some(f) = progress_cb.as_mut() {
+                f(Some(len), bytes);
+            }
+        }
+        if let Some(i) = remaining.iter().position(|&r| r > 0) {
+            return Err(Error::Segment {
+                url: self.url.clone(),
+                start: ranges[i].0,
+                end: ranges[i].1,
+                reason: "ended early",
+            });
+        }
+        f.flush().await?;
+        Ok(bytes)
+    }
+    /// Verify the checksum, if any, against the `actual` digest and move the
+    /// file into place.
+    async fn finish(
+        &self,
+        mut f: AtomicFile,
+        filename: &Path,
+        actual: Option<Vec<u8>>,
+        fresh: Validators,
+    ) -> Result<(), Error> {
+        if let (Some(actual), Some(expected)) = (actual, &self.checksum) {
+            if !expected.matches(&actual) {
+                f.discard().await?;
+                return Err(Error::ChecksumMismatch {
+                    path: filename.to_owned(),
+                    expected: expected.clone(),
+                    actual: hex::encode(actual),
+                });
+            }
+        }
+        f.commit().await?;
+        if self.overwrite == OverwriteBehaviour::CheckFreshness {
+            fresh.save(filename).await?;
+        }
+        Ok(())
+    }
+    pub async fn download<F>(
+        &self,
+        client: &Client,
+        progress_cb: Option<F>,
+    ) -> Result<(PathBuf, Outcome), Error>
+    where
+        F: FnMut(Option<u64>, u64),
+    {
+        self.download_with_retry(client, progress_cb, |_, _, _| {})
+            .await
+            .1
+    }
+    /// Download, retrying transient failures according to the `RetryPolicy`.
+    ///
+    /// `on_retry` is called with the number of the failed attempt, its error
+    /// and the delay before the next one. Returns the number of attempts made
+    /// alongside the final result.
+    pub async fn download_with_retry<F, R>(
+        &self,
+        client: &Client,
+        progress_cb: Option<F>,
+        on_retry: R,
+    ) -> (u32, Result<(PathBuf, Outcome), Error>)
+    where
+        F: FnMut(Option<u64>, u64),
+        R: FnMut(u32, &Error, Duration),
+    {
+        self.download_observed(client, progress_cb, on_retry, |_, _| {})
+            .await
+    }
+    /// `download_with_retry`, also calling `on_response` with the resolved
+    /// path and length once the response headers are in.
+    pub(crate) async fn download_observed<F, R, H>(
+        &self,
+        client: &Client,
+        mut progress_cb: Option<F>,
+        mut on_retry: R,
+        mut on_response: H,
+    ) -> (u32, Result<(PathBuf, Outcome), Error>)
+    where
+        F: FnMut(Option<u64>, u64),
+        R: FnMut(u32, &Error, Duration),
+        H: FnMut(&Path, Option<u64>),
+    {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let delay = match self
+                .download_once(client, progress_cb.as_mut(), &mut on_response)
+                .await
+            {
+                Err(e) => match self.retry.as_ref().and_then(|p| p.retry_delay(attempt, &e)) {
+                    Some(d) => {
+                        log::warn!(
+                            "Attempt {attempt} to download '{}' failed: {e}. retrying in {d:?}...",
+                            self.url
+                        );
+                        on_retry(attempt, &e, d);
+                        d
+                    }
+                    None => return (attempt, Err(e)),
+                },
+                res => return (attempt, res),
+            };
+            sleep(delay).await;
+        }
+    }
+    async fn download_once<F, H>(
+        &self,
+        client: &Client,
+        mut progress_cb: Option<F>,
+        on_response: &mut H,
+    ) -> Result<(PathBuf, Outcome), Error>
+    where
+        F: FnMut(Option<u64>, u64),
+        H: FnMut(&Path, Option<u64>),
+    {
+        if self.overwrite == OverwriteBehaviour::CheckHash
+            && !self.expect_filename()
+            && self.target.is_file()
+            && self.matches_checksum(&self.target).await?
+        {
+            // no need to touch the network
+            return Ok((self.target.clone(), Outcome::Existing));
+        }
+        // the conditional request is only possible if we know the file already
+        let cached = if self.overwrite == OverwriteBehaviour::CheckFreshness
+            && !self.expect_filename()
+            && self.target.is_file()
+        {
+            Validators::for_file(&self.target).await
+        } else {
+            None
+        };
+        let preflight = self.should_preflight();
+        let mut req = client.request(
+            if preflight { Method::HEAD } else { Method::GET },
+            &self.url,
+        );
+        if let Some(v) = &cached {
+            req = v.apply(req);
+        }
+        let r = req.send().await.map_err(|source| Error::Request {
+            url: self.url.clone(),
+            source,
+        })?;
+        // TODO: fallback to GET if we get a 405 Method Not Allowed?
+        let r = check_status(r, preflight)?;
+        if r.status() == StatusCode::NOT_MODIFIED {
+            return Ok((self.target.clone(), Outcome::Existing));
+        }
+        let len = self.content_length(&r)?;
+        let filename: Cow<'_, Path> = self.filename(&r)?.map_or_else(
+            || Cow::Borrowed(self.target.as_path()),
             |f| Cow::Owned(self.target.join(f)),
         );
-        let outcome = if filename.exists() {
+        on_response(&filename, len);
+        let existed = filename.exists();
+        if existed {
             if !filename.is_file() {
-                return Err(format!(
-                    "File exists and is not a regular file: '{}'",
-                    filename.display()
-                )
-                .into());
+                return Err(Error::NotAFile {
+                    path: filename.into_owned(),
+                });
             }
             match self.overwrite {
                 OverwriteBehaviour::Never => return Ok((filename.into_owned(), Outcome::Existing)),
                 OverwriteBehaviour::Fail => {
-                    return Err(
-                        format!("File '{}' already exists. failing!", filename.display()).into(),
-                    )
+                    return Err(Error::FileExists {
+                        path: filename.into_owned(),
+                    })
                 }
                 OverwriteBehaviour::Always => (),
                 OverwriteBehaviour::CheckLength => {
-                    let meta = filename.metadata()?;
-                    if meta.len() != len {
-                        log::info!(
+                    let meta = filename
+                        .metadata()
+                        .map_err(Error::io("Could not read metadata of", &filename))?;
+                    match len {
+                        Some(len) if meta.len() == len => {
+                            return Ok((filename.into_owned(), Outcome::Existing));
+                        }
+                        Some(_) => log::info!(
                             "File '{}' is not the expected size... overwriting...",
                             filename.display()
-                        );
-                    } else {
+                        ),
+                        None => log::info!(
+                            "No content-length to compare '{}' against... overwriting...",
+                            filename.display()
+                        ),
+                    }
+                }
+                OverwriteBehaviour::CheckHash => {
+                    if self.matches_checksum(&filename).await? {
                         return Ok((filename.into_owned(), Outcome::Existing));
                     }
+                    log::info!(
+                        "File '{}' does not match the expected checksum... overwriting...",
+                        filename.display()
+                    );
+                }
+                OverwriteBehaviour::CheckFreshness => {
+                    if Validators::for_file(&filename)
+                        .await
+                        .is_some_and(|v| v.matches(&Validators::from_headers(r.headers())))
+                    {
+                        return Ok((filename.into_owned(), Outcome::Existing));
+                    }
+                    log::info!(
+                        "File '{}' has changed on the server... overwriting...",
+                        filename.display()
+                    );
                 }
             }
-            Outcome::Redownload(len)
+        } else if let Some(parent) = filename.parent() {
+            create_dir_all(parent)
+                .await
+                .map_err(Error::io("Could not create directory", parent))?;
+        }
+        let validator = range_validator(r.headers());
+        let (mut f, offset) = if self.resume {
+            AtomicFile::resume(&filename, validator.as_deref()).await?
         } else {
-            if let Some(parent) = filename.parent() {
-                create_dir_all(parent).await?;
-            }
-            Outcome::Download(len)
+            (AtomicFile::open(&filename).await?, 0)
         };
-        let r = if preflight {
-            client.get(&self.url).send().await?.error_for_status()?
+        if let Some(n) = self.segment_count(&r, len).filter(|_| offset == 0) {
+            let fresh = Validators::from_headers(r.headers());
+            let first = (!preflight).then_some(r);
+            let len = len.expect("segments need a length");
+            let bytes = match self
+                .download_segments(
+                    client,
+                    first,
+                    len,
+                    n,
+                    validator.as_deref(),
+                    &mut f,
+                    progress_cb,
+                )
+                .await
+            {
+                Ok(b) => b,
+                Err(e) => {
+                    // the partial file has holes, so cannot be continued; its
+                    // validator goes too, so a retry starts afresh
+                    let _ = f.discard().await;
+                    return Err(e);
+                }
+            };
+            let actual = match &self.checksum {
+                Some(c) => {
+                    let mut h = c.hasher();
+                    h.update_from_file(f.temp_path(), None).await?;
+                    Some(h.finalize())
+                }
+                None => None,
+            };
+            self.finish(f, &filename, actual, fresh).await?;
+            let outcome = if existed {
+                Outcome::Redownload(bytes)
+            } else {
+                Outcome::Download(bytes)
+            };
+            return Ok((filename.into_owned(), outcome));
+        }
+        let (r, offset) = if offset > 0 && len.is_some_and(|len| offset < len) {
+            drop(r);
+            let mut req = client
+                .get(&self.url)
+                .header(RANGE, format!("bytes={offset}-"));
+            if let Some(v) = validator {
+                req = req.header(IF_RANGE, v);
+            }
+            let r = req.send().await.map_err(|source| Error::Request {
+                url: self.url.clone(),
+                source,
+            })?;
+            let r = check_status(r, false)?;
+            if r.status() == StatusCode::PARTIAL_CONTENT {
+                if content_range_start(r.headers()) != Some(offset) {
+                    return Err(Error::ContentRange {
+                        url: self.url.clone(),
+                        offset,
+                    });
+                }
+                (r, offset)
+            } else {
+                // If-Range did not match, so the server is sending the full body
+                log::info!(
+                    "Partial file for '{}' is stale... restarting...",
+                    filename.display()
+                );
+                f.truncate().await?;
+                (r, 0)
+            }
         } else {
-            r
+            if offset > 0 {
+                f.truncate().await?;
+            }
+            let r = if preflight {
+                let r = client
+                    .get(&self.url)
+                    .send()
+                    .await
+                    .map_err(|source| Error::Request {
+                        url: self.url.clone(),
+                        source,
+                    })?;
+                check_status(r, false)?
+            } else {
+                r
+            };
+            (r, 0)
         };
-        let mut f = crate::file::AtomicFile::open(&filename.as_ref())
-            .await
-            .map_err(|e| format!("Could not open tempfile for writing: {e}"))?;
+        // the body we stream may differ from the preflight or first response
+        let len = self.content_length(&r)?.map(|l| l + offset);
+        let fresh = Validators::from_headers(r.headers());
+        let mut hasher = self.checksum.as_ref().map(|c| c.hasher());
+        if let (Some(h), true) = (hasher.as_mut(), offset > 0) {
+            h.update_from_file(f.temp_path(), Some(offset)).await?;
+        }
         let mut bytestream = r.bytes_stream();
-        let mut bytes = 0;
+        let mut bytes = offset;
         if let Some(f) = progress_cb.as_mut() {
-            f(len, 0);
+            f(len, offset);
         }
-        while let Some(v) = bytestream.next().await {
-            let b = v.map_err(|e| format!("Error streaming bytes from HTTP response: {e}"))?;
-            bytes += b.len();
-            f.write_all(&b)
-                .await
-                .map_err(|e| format!("Error writing bytes to tempfile: {e}"))?;
+        loop {
+            if let Some(p) = &self.pause {
+                p.wait().await;
+            }
+            let Some(v) = bytestream.next().await else {
+                break;
+            };
+            let b = v.map_err(|source| Error::Stream {
+                url: self.url.clone(),
+                source,
+            })?;
+            bytes += b.len() as u64;
+            for l in &self.rate_limits {
+                l.acquire(b.len() as u64).await;
+            }
+            if let Some(h) = hasher.as_mut() {
+                h.update(&b);
+            }
+            f.write_all(&b).await?;
             if let Some(f) = progress_cb.as_mut() {
-                f(len, bytes as u64);
+                f(len, bytes);
             }
         }
-        f.commit()
-            .await
-            .map_err(|e| format!("Error committing written file: {e}"))?;
+        self.finish(f, &filename, hasher.map(|h| h.finalize()), fresh)
+            .await?;
+        let outcome = if offset > 0 {
+            Outcome::Resumed(offset, bytes)
+        } else if existed {
+            Outcome::Redownload(bytes)
+        } else {
+            Outcome::Download(bytes)
+        };
         Ok((filename.into_owned(), outcome))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, sync::atomic::Ordering};
+
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::file::partial_path;
+    use crate::test_util::{serve, Served, ETAG_VALUE};
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn resumable(url: &str, dir: &Path) -> FileDownload {
+        FileDownload::builder()
+            .url(format!("{url}/f"))
+            .target(dir.to_owned())
+            .filename(Some("f".to_string()))
+            .title(None)
+            .resume(true)
+            .retry(
+                RetryPolicy::builder()
+                    .base_delay(Duration::from_millis(10))
+                    .jitter(false)
+                    .build()
+                    .unwrap(),
+            )
+            .build()
+            .unwrap()
+    }
+
+    // a partial file as left by an earlier run against the served validator
+    fn leave_partial(dir: &Path, content: &[u8]) {
+        let part = partial_path(&dir.join("f")).unwrap();
+        std::fs::write(&part, content).unwrap();
+        let mut v = part.into_os_string();
+        v.push(".validator");
+        std::fs::write(v, ETAG_VALUE).unwrap();
+    }
+
+    #[tokio::test]
+    async fn resume_continues_partial() {
+        let server = serve(Served::new(data(1000)));
+        let dir = tempdir().unwrap();
+        leave_partial(dir.path(), &data(1000)[..400]);
+        let dl = resumable(&server.url, dir.path());
+        let (path, outcome) = dl
+            .download(&get_client(None).unwrap(), None::<fn(_, _)>)
+            .await
+            .unwrap();
+        assert!(
+            matches!(outcome, Outcome::Resumed(400, 1000)),
+            "{outcome:?}"
+        );
+        assert_eq!(std::fs::read(path).unwrap(), data(1000));
+        assert_eq!(server.served.ranges_requested(), ["bytes=400-"]);
+    }
+
+    #[tokio::test]
+    async fn resume_restarts_when_range_ignored() {
+        let served = Served::new(data(1000));
+        served.ranges.store(false, Ordering::SeqCst);
+        let server = serve(served);
+        let dir = tempdir().unwrap();
+        leave_partial(dir.path(), &[b'x'; 400]);
+        let dl = resumable(&server.url, dir.path());
+        let (path, outcome) = dl
+            .download(&get_client(None).unwrap(), None::<fn(_, _)>)
+            .await
+            .unwrap();
+        assert!(matches!(outcome, Outcome::Download(1000)), "{outcome:?}");
+        assert_eq!(std::fs::read(path).unwrap(), data(1000));
+        assert_eq!(server.served.ranges_requested(), ["bytes=400-"]);
+    }
+
+    #[tokio::test]
+    async fn resume_restarts_when_partial_is_not_shorter() {
+        let server = serve(Served::new(data(1000)));
+        for len in [1000, 1200] {
+            let dir = tempdir().unwrap();
+            leave_partial(dir.path(), &vec![b'x'; len]);
+            let dl = resumable(&server.url, dir.path());
+            let (path, outcome) = dl
+                .download(&get_client(None).unwrap(), None::<fn(_, _)>)
+                .await
+                .unwrap();
+            assert!(matches!(outcome, Outcome::Download(1000)), "{outcome:?}");
+            assert_eq!(std::fs::read(path).unwrap(), data(1000));
+        }
+        assert!(server.served.ranges_requested().is_empty());
+    }
+
+    #[tokio::test]
+    async fn segmented_resume_retry_and_rerun() {
+        let len = 2 * MIN_SEGMENT_SIZE as usize + 10;
+        let served = Served::new(data(len));
+        // fail the first attempt's second segment
+        served.fail_ranges.store(1, Ordering::SeqCst);
+        let server = serve(served);
+        let dir = tempdir().unwrap();
+        let mut dl = resumable(&server.url, dir.path());
+        dl.segments = 2;
+        let client = get_client(None).unwrap();
+        let (attempts, res) = dl
+            .download_with_retry(&client, None::<fn(_, _)>, |_, _, _| {})
+            .await;
+        let (path, _) = res.unwrap();
+        assert_eq!(attempts, 2);
+        assert!(std::fs::read(&path).unwrap() == data(len), "corrupt file");
+
+        // an empty partial file from a run killed before writing anything
+        std::fs::remove_file(&path).unwrap();
+        leave_partial(dir.path(), b"");
+        let (path, outcome) = dl.download(&client, None::<fn(_, _)>).await.unwrap();
+        assert!(matches!(outcome, Outcome::Download(_)), "{outcome:?}");
+        assert!(std::fs::read(&path).unwrap() == data(len), "corrupt file");
+        assert!(!partial_path(&path).unwrap().exists());
+    }
+}
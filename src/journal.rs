@@ -0,0 +1,152 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::fs::{read_to_string, OpenOptions};
+
+use crate::http::FileDownload;
+use crate::operation::DownloadReport;
+use crate::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JournalState {
+    Started,
+    Completed,
+    Failed,
+}
+
+/// One line of the journal. The latest line for a download wins.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub state: JournalState,
+    pub url: String,
+    pub target: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+type Key = (String, PathBuf);
+
+struct Inner {
+    path: PathBuf,
+    file: Mutex<File>,
+    entries: Mutex<HashMap<Key, JournalEntry>>,
+}
+
+/// An append-only record of the downloads of an `Operation`, as JSON lines.
+///
+/// A rerun with the same journal skips downloads recorded as completed whose
+/// file is still there, without touching the network, and resumes the rest
+/// from their partial files.
+#[derive(Clone)]
+pub struct Journal(Arc<Inner>);
+
+impl Journal {
+    /// Open or create the journal at `path`, loading its entries.
+    pub async fn open<P>(path: P) -> Result<Self, Error>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref().to_owned();
+        let mut entries = HashMap::new();
+        if path.is_file() {
+            let text = read_to_string(&path)
+                .await
+                .map_err(Error::io("Could not read journal", &path))?;
+            for line in text.lines().filter(|l| !l.trim().is_empty()) {
+                match serde_json::from_str::<JournalEntry>(line) {
+                    Ok(e) => {
+                        entries.insert((e.url.clone(), e.target.clone()), e);
+                    }
+                    // most likely a line cut short by a crash
+                    Err(e) => log::warn!("Ignoring bad line in '{}': {e}", path.display()),
+                }
+            }
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await
+            .map_err(Error::io("Could not open journal", &path))?
+            .into_std()
+            .await;
+        Ok(Self(Arc::new(Inner {
+            path,
+            file: Mutex::new(file),
+            entries: Mutex::new(entries),
+        })))
+    }
+    /// The journal at `dir/.dlcommon-journal`.
+    pub async fn in_dir<P>(dir: P) -> Result<Self, Error>
+    where
+        P: AsRef<Path>,
+    {
+        Self::open(dir.as_ref().join(".dlcommon-journal")).await
+    }
+    pub fn path(&self) -> &Path {
+        &self.0.path
+    }
+    pub fn get(&self, download: &FileDownload) -> Option<JournalEntry> {
+        let key = (download.url.clone(), download.target.clone());
+        self.0.entries.lock().unwrap().get(&key).cloned()
+    }
+    /// Where `download` was saved, if it completed and the file still exists.
+    pub fn completed(&self, download: &FileDownload) -> Option<PathBuf> {
+        self.get(download)
+            .filter(|e| e.state == JournalState::Completed)
+            .and_then(|e| e.path)
+            .filter(|p| p.is_file())
+    }
+    pub fn record(&self, entry: JournalEntry) -> Result<(), Error> {
+        let mut line = serde_json::to_string(&entry).expect("journal entries serialize");
+        line.push('\n');
+        {
+            let mut file = self.0.file.lock().unwrap();
+            file.write_all(line.as_bytes())
+                .and_then(|_| file.flush())
+                .map_err(Error::io("Could not write journal", &self.0.path))?;
+        }
+        self.0
+            .entries
+            .lock()
+            .unwrap()
+            .insert((entry.url.clone(), entry.target.clone()), entry);
+        Ok(())
+    }
+    fn record_or_warn(&self, entry: JournalEntry) {
+        if let Err(e) = self.record(entry) {
+            log::warn!("{e}");
+        }
+    }
+    pub(crate) fn started(&self, download: &FileDownload) {
+        self.record_or_warn(JournalEntry {
+            state: JournalState::Started,
+            url: download.url.clone(),
+            target: download.target.clone(),
+            path: None,
+            error: None,
+        });
+    }
+    pub(crate) fn finished(&self, report: &DownloadReport) {
+        let (state, path, error) = match &report.result {
+            Ok((path, _)) => (JournalState::Completed, Some(path.clone()), None),
+            Err(e) => (JournalState::Failed, None, Some(e.to_string())),
+        };
+        self.record_or_warn(JournalEntry {
+            state,
+            url: report.download.url.clone(),
+            target: report.download.target.clone(),
+            path,
+            error,
+        });
+    }
+}
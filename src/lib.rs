@@ -1,5 +1,20 @@
+pub mod checksum;
+pub mod cli;
+pub mod control;
 pub mod cookies;
+pub mod error;
+pub mod event;
 pub mod file;
 pub mod http;
+pub mod journal;
+pub mod manifest;
 pub mod operation;
+pub mod ratelimit;
+pub mod reporter;
+pub mod retry;
+pub mod scheduler;
 pub mod style;
+#[cfg(test)]
+mod test_util;
+
+pub use error::Error;
@@ -0,0 +1,141 @@
+use std::{path::PathBuf, process::ExitCode, sync::Arc};
+
+use clap::Parser;
+use indicatif::MultiProgress;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use reqwest_cookie_store::CookieStoreMutex;
+
+use dlcommon::{
+    cli::{CookieArgs, DownloadArgs, OperationArgs},
+    http::{get_client, FileDownloadBuilder},
+    journal::Journal,
+    manifest::{Manifest, ManifestFormat},
+    operation::{CancelPolicy, OperationBuilder, OperationReport},
+    Error,
+};
+
+/// Download files over HTTP.
+#[derive(Debug, Parser)]
+#[command(version)]
+struct Cli {
+    /// URLs to download.
+    #[arg(required_unless_present = "input")]
+    urls: Vec<String>,
+    /// Also download the entries of a manifest file.
+    #[arg(short, long, value_name = "FILE")]
+    input: Option<PathBuf>,
+    /// Manifest format: urls, csv, tsv, json, jsonl or aria2. Guessed from
+    /// the file extension by default. The json formats ignore the download
+    /// flags below, except `--target` for entries without one.
+    #[arg(long, value_parser = clap::value_parser!(ManifestFormat), requires = "input")]
+    format: Option<ManifestFormat>,
+    #[command(flatten)]
+    download: DownloadArgs,
+    #[command(flatten)]
+    operation: OperationArgs,
+    /// Record progress in `.dlcommon-journal` in the target directory and
+    /// skip downloads it lists as completed.
+    #[arg(long)]
+    journal: bool,
+    #[command(flatten)]
+    cookies: CookieArgs,
+    /// Log debug messages.
+    #[arg(short, long)]
+    verbose: bool,
+}
+
+/// Logs to stderr, clearing the progress bars while it writes.
+struct StderrLogger(Arc<MultiProgress>);
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.target().starts_with("dlcommon") || metadata.level() <= Level::Warn
+    }
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.0
+                .suspend(|| eprintln!("[{}] {}", record.level(), record.args()));
+        }
+    }
+    fn flush(&self) {}
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let progress = Arc::new(MultiProgress::new());
+    let _ = log::set_logger(Box::leak(Box::new(StderrLogger(progress.clone()))));
+    log::set_max_level(if cli.verbose {
+        LevelFilter::Debug
+    } else {
+        LevelFilter::Info
+    });
+    match run(cli, progress.clone()).await {
+        // above the finished bars
+        Ok(report) => progress.suspend(|| summarize(&report)),
+        Err(e) => {
+            eprintln!("Error: {e}");
+            ExitCode::from(2)
+        }
+    }
+}
+
+async fn run(cli: Cli, progress: Arc<MultiProgress>) -> Result<OperationReport, Error> {
+    let template = FileDownloadBuilder::from(&cli.download);
+    let mut manifest = Manifest::parse(&cli.urls.join("\n"), ManifestFormat::Urls, &template)?;
+    if let Some(input) = &cli.input {
+        let m = Manifest::load(input, cli.format, &template).await?;
+        manifest.downloads.extend(m.downloads);
+    }
+
+    let cookies = Arc::new(CookieStoreMutex::try_from(&cli.cookies)?);
+    let mut op = OperationBuilder::from(&cli.operation);
+    // aborting keeps the partial files of resumable downloads (`--resume`,
+    // `--journal`)
+    op.client(get_client(Some(cookies.clone()))?)
+        .multiprogress(progress)
+        .handle_signals(true)
+        .cancel_policy(CancelPolicy::Abort);
+    if cli.journal {
+        let target = &cli.download.target;
+        tokio::fs::create_dir_all(target)
+            .await
+            .map_err(|source| Error::Io {
+                context: "Could not create directory",
+                path: target.clone(),
+                source,
+            })?;
+        op.journal(Journal::in_dir(target).await?);
+    }
+    let report = op
+        .build()
+        .expect("client and defaults are set")
+        .run(manifest)
+        .await?;
+    cli.cookies.save(&cookies)?;
+    Ok(report)
+}
+
+// failures were reported as they happened
+fn summarize(report: &OperationReport) -> ExitCode {
+    eprintln!(
+        "Downloaded {} of {} items ({} existing, {} bytes), {} failed",
+        report.succeeded(),
+        report.total(),
+        report.existing(),
+        report.bytes(),
+        report.failed()
+    );
+    if report.cancelled {
+        eprintln!(
+            "Cancelled with {} downloads not attempted",
+            report.not_attempted.len()
+        );
+    }
+    if report.failed() > 0 || report.cancelled {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
@@ -0,0 +1,394 @@
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+};
+
+use reqwest::Url;
+use serde::Deserialize;
+use strum::{Display, EnumString};
+use tokio::fs::read_to_string;
+
+use crate::checksum::Checksum;
+use crate::http::{filename_from_url, sanitize_filename, FileDownload, FileDownloadBuilder};
+use crate::operation::Source;
+use crate::Error;
+
+/// File formats `Manifest` can read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, Display)]
+#[strum(serialize_all = "lowercase", ascii_case_insensitive)]
+pub enum ManifestFormat {
+    /// One URL per line. Blank lines and `#` comments are skipped.
+    #[strum(to_string = "urls", serialize = "txt")]
+    Urls,
+    /// A header row naming the `url`, `filename`, `title` and `hash` columns.
+    Csv,
+    /// As `Csv`, separated by tabs.
+    Tsv,
+    /// An array of serialized `FileDownload`s.
+    Json,
+    /// One serialized `FileDownload` per line.
+    #[strum(to_string = "jsonl", serialize = "ndjson")]
+    Jsonl,
+    /// The aria2c input file format: a URI per line (further tab-separated
+    /// URIs are mirrors and ignored), followed by indented `out=`, `dir=`
+    /// and `checksum=` options.
+    Aria2,
+}
+
+impl ManifestFormat {
+    /// Guess the format from a file extension, falling back to `Urls`.
+    pub fn from_path(p: &Path) -> Self {
+        p.extension()
+            .and_then(|e| e.to_str())
+            .and_then(|e| e.parse().ok())
+            .unwrap_or(Self::Urls)
+    }
+}
+
+// an entry of the line-based formats
+#[derive(Debug, Default, Deserialize)]
+struct Entry {
+    url: String,
+    #[serde(default, alias = "out", alias = "name")]
+    filename: Option<String>,
+    #[serde(default)]
+    title: Option<String>,
+    #[serde(default, alias = "checksum")]
+    hash: Option<String>,
+    #[serde(default)]
+    dir: Option<PathBuf>,
+}
+
+impl Entry {
+    fn build(self, template: &FileDownloadBuilder, line: usize) -> Result<FileDownload, Error> {
+        let err = |reason: String| Error::Manifest { line, reason };
+        let url = Url::parse(self.url.trim()).map_err(|e| err(format!("'{}': {e}", self.url)))?;
+        let mut b = template.clone();
+        b.title(self.title);
+        let filename = match self.filename {
+            Some(f) => {
+                Some(sanitize_filename(&f).ok_or_else(|| err(format!("invalid filename '{f}'")))?)
+            }
+            None => filename_from_url(&url),
+        };
+        if let Some(f) = filename {
+            b.filename(f);
+        }
+        if let Some(h) = self.hash {
+            b.checksum(h.parse::<Checksum>().map_err(|e| err(e.to_string()))?);
+        }
+        if let Some(d) = self.dir {
+            b.target(d);
+        }
+        b.url(url).build().map_err(|e| err(e.to_string()))
+    }
+}
+
+// a JSON entry's own settings are kept; only a missing target comes from the
+// template, along with a filename from the URL as for the other formats
+fn fill_json(
+    mut d: FileDownload,
+    template: &FileDownloadBuilder,
+    line: usize,
+) -> Result<FileDownload, Error> {
+    let err = |reason: String| Error::Manifest { line, reason };
+    if d.target.as_os_str().is_empty() {
+        d.target = template
+            .target_dir()
+            .cloned()
+            .ok_or_else(|| err(format!("'{}' has no target directory", d.url)))?;
+        if d.filename.is_none() {
+            d.filename = Url::parse(&d.url).ok().and_then(|u| filename_from_url(&u));
+        }
+    }
+    if let Some(f) = d.filename.take() {
+        d.filename =
+            Some(sanitize_filename(&f).ok_or_else(|| err(format!("invalid filename '{f}'")))?);
+    }
+    Ok(d)
+}
+
+/// Downloads read from a manifest file.
+///
+/// For all formats but the JSON ones, `template` supplies the settings an
+/// entry does not, and must set a `target` directory. JSON entries only take
+/// their `target` from it, when they have none. Entries without a
+/// filename take it from their URL; given ones are sanitized like
+/// server-provided names.
+#[derive(Debug, Clone, Default)]
+pub struct Manifest {
+    pub downloads: Vec<FileDownload>,
+}
+
+impl Manifest {
+    pub fn parse(
+        text: &str,
+        format: ManifestFormat,
+        template: &FileDownloadBuilder,
+    ) -> Result<Self, Error> {
+        let downloads = match format {
+            ManifestFormat::Urls => text
+                .lines()
+                .enumerate()
+                .filter(|(_, l)| !l.trim().is_empty() && !l.trim_start().starts_with('#'))
+                .map(|(i, l)| {
+                    let entry = Entry {
+                        url: l.trim().to_string(),
+                        ..Default::default()
+                    };
+                    entry.build(template, i + 1)
+                })
+                .collect::<Result<_, _>>()?,
+            ManifestFormat::Csv => parse_csv(text, b',', template)?,
+            ManifestFormat::Tsv => parse_csv(text, b'\t', template)?,
+            ManifestFormat::Json => {
+                let downloads: Vec<FileDownload> =
+                    serde_json::from_str(text).map_err(|e| Error::Manifest {
+                        line: e.line(),
+                        reason: e.to_string(),
+                    })?;
+                // the lines of array elements are not known, so errors point
+                // at the start of the array
+                downloads
+                    .into_iter()
+                    .map(|d| fill_json(d, template, 1))
+                    .collect::<Result<_, _>>()?
+            }
+            ManifestFormat::Jsonl => text
+                .lines()
+                .enumerate()
+                .filter(|(_, l)| !l.trim().is_empty())
+                .map(|(i, l)| {
+                    let d = serde_json::from_str(l).map_err(|e| Error::Manifest {
+                        line: i + 1,
+                        reason: e.to_string(),
+                    })?;
+                    fill_json(d, template, i + 1)
+                })
+                .collect::<Result<_, _>>()?,
+            ManifestFormat::Aria2 => parse_aria2(text, template)?,
+        };
+        Ok(Self { downloads })
+    }
+    /// Read and parse `path`, guessing the format from its extension if
+    /// none is given.
+    pub async fn load<P>(
+        path: P,
+        format: Option<ManifestFormat>,
+        template: &FileDownloadBuilder,
+    ) -> Result<Self, Error>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let text = read_to_string(path)
+            .await
+            .map_err(Error::io("Could not read manifest", path))?;
+        Self::parse(
+            &text,
+            format.unwrap_or_else(|| ManifestFormat::from_path(path)),
+            template,
+        )
+    }
+    pub fn len(&self) -> usize {
+        self.downloads.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.downloads.is_empty()
+    }
+}
+
+fn parse_csv(
+    text: &str,
+    delimiter: u8,
+    template: &FileDownloadBuilder,
+) -> Result<Vec<FileDownload>, Error> {
+    let err = |e: csv::Error| Error::Manifest {
+        line: e.position().map_or(0, |p| p.line() as usize),
+        reason: e.to_string(),
+    };
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .comment(Some(b'#'))
+        .from_reader(text.as_bytes());
+    let headers = reader.headers().map_err(err)?.clone();
+    let mut record = csv::StringRecord::new();
+    let mut downloads = vec![];
+    while reader.read_record(&mut record).map_err(err)? {
+        let line = record.position().map_or(0, |p| p.line() as usize);
+        let entry: Entry = record
+            .deserialize(Some(&headers))
+            .map_err(|e| Error::Manifest {
+                line,
+                reason: e.to_string(),
+            })?;
+        downloads.push(entry.build(template, line)?);
+    }
+    Ok(downloads)
+}
+
+fn parse_aria2(text: &str, template: &FileDownloadBuilder) -> Result<Vec<FileDownload>, Error> {
+    let mut downloads = vec![];
+    let mut current: Option<(usize, Entry)> = None;
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() || line.trim_start().starts_with('#') {
+            continue;
+        }
+        if !line.starts_with(char::is_whitespace) {
+            if let Some((n, entry)) = current.take() {
+                downloads.push(entry.build(template, n)?);
+            }
+            let url = line.split('\t').next().unwrap_or_default();
+            current = Some((
+                i + 1,
+                Entry {
+                    url: url.trim().to_string(),
+                    ..Default::default()
+                },
+            ));
+            continue;
+        }
+        let err = |reason: &str| Error::Manifest {
+            line: i + 1,
+            reason: reason.to_string(),
+        };
+        let Some((_, entry)) = current.as_mut() else {
+            return Err(err("option before the first URI"));
+        };
+        let (key, value) = line
+            .trim()
+            .split_once('=')
+            .ok_or_else(|| err("expected key=value"))?;
+        let value = value.to_string();
+        match key {
+            "out" => entry.filename = Some(value),
+            "dir" => entry.dir = Some(value.into()),
+            "checksum" => entry.hash = Some(value),
+            _ => log::debug!("Ignoring aria2 option '{key}' at line {}", i + 1),
+        }
+    }
+    if let Some((n, entry)) = current {
+        downloads.push(entry.build(template, n)?);
+    }
+    Ok(downloads)
+}
+
+impl Source for Manifest {
+    fn num_downloads(&self) -> Option<u64> {
+        Some(self.downloads.len() as u64)
+    }
+    fn apply_to_downloads<F, R>(self, f: F) -> impl Future<Output = Result<(), Error>>
+    where
+        F: Fn(FileDownload) -> R,
+        R: Future<Output = Result<(), Error>>,
+    {
+        self.downloads.apply_to_downloads(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA1: &str = "da39a3ee5e6b4b0d3255bfef95601890afd80709";
+
+    fn template() -> FileDownloadBuilder {
+        let mut t = FileDownloadBuilder::default();
+        t.target("dl");
+        t
+    }
+
+    fn checksum(d: &FileDownload) -> serde_json::Value {
+        serde_json::to_value(d).unwrap()["checksum"].clone()
+    }
+
+    #[test]
+    fn urls() {
+        let text = "# files\nhttp://a.test/x/one.bin\n\n  http://a.test/two%20b\n";
+        let m = Manifest::parse(text, ManifestFormat::Urls, &template()).unwrap();
+        assert_eq!(m.len(), 2);
+        assert_eq!(m.downloads[0].filename.as_deref(), Some("one.bin"));
+        assert_eq!(m.downloads[1].filename.as_deref(), Some("two b"));
+        assert_eq!(m.downloads[1].target, Path::new("dl"));
+        let e = Manifest::parse("http://a\nnot a url", ManifestFormat::Urls, &template());
+        assert!(matches!(e, Err(Error::Manifest { line: 2, .. })));
+    }
+
+    #[test]
+    fn csv_aliases() {
+        let text = format!(
+            "url,name,checksum,title\n\
+             http://a.test/1,one.bin,sha1:{SHA1},First\n\
+             # skipped\n\
+             http://a.test/2\n"
+        );
+        let m = Manifest::parse(&text, ManifestFormat::Csv, &template()).unwrap();
+        assert_eq!(m.len(), 2);
+        let d = &m.downloads[0];
+        assert_eq!(d.filename.as_deref(), Some("one.bin"));
+        assert_eq!(d.title.as_deref(), Some("First"));
+        assert_eq!(checksum(d), format!("sha1:{SHA1}"));
+        assert_eq!(m.downloads[1].filename.as_deref(), Some("2"));
+
+        let text = "out\turl\nsub/a\thttp://a.test/1\n";
+        let m = Manifest::parse(text, ManifestFormat::Tsv, &template()).unwrap();
+        assert_eq!(m.downloads[0].filename.as_deref(), Some("a"));
+        let e = Manifest::parse(
+            "url,name\nhttp://a.test/1,..\n",
+            ManifestFormat::Csv,
+            &template(),
+        );
+        assert!(matches!(e, Err(Error::Manifest { line: 2, .. })));
+    }
+
+    #[test]
+    fn aria2() {
+        let text = format!(
+            "http://a.test/1\thttp://mirror.test/1\n\
+             \x20 out=one.bin\n\
+             \tdir=elsewhere\n\
+             \x20 checksum=sha-1={SHA1}\n\
+             \x20 split=4\n\
+             # comment\n\
+             http://a.test/2\n"
+        );
+        let m = Manifest::parse(&text, ManifestFormat::Aria2, &template()).unwrap();
+        assert_eq!(m.len(), 2);
+        let d = &m.downloads[0];
+        assert_eq!(d.url, "http://a.test/1");
+        assert_eq!(d.filename.as_deref(), Some("one.bin"));
+        assert_eq!(d.target, Path::new("elsewhere"));
+        assert_eq!(checksum(d), format!("sha1:{SHA1}"));
+        assert_eq!(m.downloads[1].target, Path::new("dl"));
+        assert_eq!(checksum(&m.downloads[1]), serde_json::Value::Null);
+
+        let e = Manifest::parse("  out=x\n", ManifestFormat::Aria2, &template());
+        assert!(matches!(e, Err(Error::Manifest { line: 1, .. })));
+        let e = Manifest::parse(
+            "http://a.test/1\n  out\n",
+            ManifestFormat::Aria2,
+            &template(),
+        );
+        assert!(matches!(e, Err(Error::Manifest { line: 2, .. })));
+    }
+
+    #[test]
+    fn json() {
+        let text = r#"[{"url": "http://a.test/1"},
+            {"url": "http://a.test/2", "target": "own", "filename": "../x"}]"#;
+        let m = Manifest::parse(text, ManifestFormat::Json, &template()).unwrap();
+        assert_eq!(m.downloads[0].target, Path::new("dl"));
+        assert_eq!(m.downloads[0].filename.as_deref(), Some("1"));
+        assert_eq!(m.downloads[1].target, Path::new("own"));
+        assert_eq!(m.downloads[1].filename.as_deref(), Some("x"));
+
+        let text = "{\"url\": \"http://a.test/1\", \"title\": null}\n\n{}\n";
+        let e = Manifest::parse(text, ManifestFormat::Jsonl, &template());
+        assert!(matches!(e, Err(Error::Manifest { line: 3, .. })));
+        let text = "{\"url\": \"http://a.test/1\", \"title\": null}\n";
+        let e = Manifest::parse(text, ManifestFormat::Jsonl, &FileDownloadBuilder::default());
+        assert!(matches!(e, Err(Error::Manifest { line: 1, .. })));
+    }
+}
@@ -1,16 +1,46 @@
-use std::{cell::RefCell, error::Error, future::Future, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    future::Future,
+    io::{stderr, IsTerminal},
+    path::PathBuf,
+    pin::pin,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
 use derive_builder::Builder;
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use futures_util::{
+    future::{join, pending, ready, select, Either, FutureExt},
+    Stream, StreamExt,
+};
+use indicatif::{MultiProgress, ProgressStyle};
 use reqwest::Client;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::{
+    signal::ctrl_c,
     spawn,
-    sync::{OwnedSemaphorePermit, Semaphore},
+    sync::{
+        mpsc::{Receiver, UnboundedReceiver, UnboundedSender},
+        OwnedSemaphorePermit, Semaphore,
+    },
     time::sleep,
 };
 
-use crate::http::FileDownload;
-use crate::style::*;
+use tokio_util::sync::CancellationToken;
+
+use crate::control::{Counters, OperationControl, PauseGate};
+use crate::event::{channel_observer, DownloadEvent, EventReporter, Observer};
+use crate::http::{FileDownload, Outcome};
+use crate::journal::Journal;
+use crate::ratelimit::RateLimiter;
+use crate::reporter::{LogReporter, ProgressReporter, Reporter, Reporters};
+use crate::retry::RetryPolicy;
+use crate::scheduler::{HostLimits, HostSlot, Scheduler};
+use crate::Error;
 
 #[derive(Clone, Builder)]
 pub struct Operation {
@@ -18,20 +48,145 @@ pub struct Operation {
     client: Arc<Client>,
     #[builder(default = "Arc::new(Semaphore::new(1))", setter(custom))]
     concurrency: Arc<Semaphore>,
+    #[builder(default = "Arc::new(AtomicUsize::new(1))", setter(custom))]
+    concurrency_limit: Arc<AtomicUsize>,
+    /// Permits to forget as they are acquired, after lowering the concurrency.
+    #[builder(setter(skip))]
+    concurrency_debt: Arc<AtomicUsize>,
     #[builder(default, setter(into, strip_option))]
     multiprogress: Option<Arc<MultiProgress>>,
-    #[builder(default = "Duration::from_secs(1)", setter(custom))]
+    /// A fixed sleep after each download, holding its concurrency slot. None
+    /// by default; `HostLimits::min_interval` is usually the better tool.
+    #[builder(default = "Duration::ZERO", setter(custom))]
     wait_after_download: Duration,
+    /// Limits applied to every host without its own entry.
+    #[builder(default, setter(into))]
+    host_limits: HostLimits,
+    #[builder(default, setter(custom))]
+    per_host_limits: HashMap<String, HostLimits>,
     #[builder(default, setter(into, strip_option))]
     main_progress_style: Option<ProgressStyle>,
     #[builder(default, setter(into, strip_option))]
     spin_progress_style: Option<ProgressStyle>,
     #[builder(default, setter(into, strip_option))]
     item_progress_style: Option<ProgressStyle>,
+    /// Used instead of `item_progress_style` when the length is unknown.
+    #[builder(default, setter(into, strip_option))]
+    item_spinner_style: Option<ProgressStyle>,
     #[builder(default, setter(into, strip_option))]
     item_success_style: Option<ProgressStyle>,
     #[builder(default, setter(into, strip_option))]
     item_failure_style: Option<ProgressStyle>,
+    /// Retry policy for downloads that do not set their own.
+    #[builder(default, setter(into, strip_option))]
+    retry: Option<RetryPolicy>,
+    /// Bandwidth limit shared by all downloads, in addition to their own.
+    /// Keep a clone to adjust it while `run` is in progress.
+    #[builder(default, setter(into, strip_option))]
+    rate_limit: Option<RateLimiter>,
+    /// Return `Error::Incomplete` from `run` if any download failed.
+    #[builder(default)]
+    fail_on_error: bool,
+    /// Where progress goes. Defaults to progress bars using the styles above
+    /// when stderr is a terminal, and to `LogReporter` otherwise.
+    #[builder(default, setter(custom))]
+    reporter: Option<Arc<dyn Reporter>>,
+    #[builder(default, setter(custom))]
+    observers: Vec<Observer>,
+    /// Cancelling this stops `run` from starting further downloads.
+    #[builder(default)]
+    cancel_token: CancellationToken,
+    #[builder(default)]
+    cancel_policy: CancelPolicy,
+    /// Cancel on SIGINT or SIGTERM while `run` is in progress. A second
+    /// signal aborts running downloads whatever the `cancel_policy`, and a
+    /// third exits the process. The default handlers stay replaced
+    /// afterwards.
+    #[builder(default)]
+    handle_signals: bool,
+    /// Skip downloads the journal has as completed, and record progress to
+    /// it. Downloads become resumable.
+    #[builder(default, setter(into, strip_option))]
+    journal: Option<Journal>,
+    #[builder(setter(skip))]
+    pause: PauseGate,
+    #[builder(setter(skip))]
+    counters: Arc<Counters>,
+}
+
+/// What happens to running downloads when an `Operation` is cancelled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CancelPolicy {
+    /// Let them run to completion.
+    #[default]
+    Finish,
+    /// Stop them with `Error::Cancelled`. Partial files are removed, or kept
+    /// for downloads that can resume.
+    Abort,
+}
+
+/// The result of a single download within an `Operation`.
+#[derive(Debug)]
+pub struct DownloadReport {
+    pub download: FileDownload,
+    pub result: Result<(PathBuf, Outcome), Error>,
+    /// Bytes received over the network, across all attempts.
+    pub bytes: u64,
+    pub duration: Duration,
+    pub attempts: u32,
+}
+
+impl DownloadReport {
+    pub fn is_ok(&self) -> bool {
+        self.result.is_ok()
+    }
+    pub fn path(&self) -> Option<&PathBuf> {
+        self.result.as_ref().ok().map(|(p, _)| p)
+    }
+    pub fn outcome(&self) -> Option<Outcome> {
+        self.result.as_ref().ok().map(|(_, o)| *o)
+    }
+    pub fn error(&self) -> Option<&Error> {
+        self.result.as_ref().err()
+    }
+}
+
+/// Per-download results of `Operation::run`, in the order the source yielded
+/// the downloads.
+#[derive(Debug, Default)]
+pub struct OperationReport {
+    pub items: Vec<DownloadReport>,
+    /// Whether the run was cancelled.
+    pub cancelled: bool,
+    /// Downloads never started due to cancellation. A source that knows its
+    /// `num_downloads` is drained into this; others, such as channels and
+    /// streams, only report what they had yielded.
+    pub not_attempted: Vec<FileDownload>,
+}
+
+impl OperationReport {
+    pub fn total(&self) -> usize {
+        self.items.len()
+    }
+    pub fn succeeded(&self) -> usize {
+        self.items.iter().filter(|i| i.is_ok()).count()
+    }
+    pub fn failed(&self) -> usize {
+        self.total() - self.succeeded()
+    }
+    /// Downloads that were skipped because the file already existed.
+    pub fn existing(&self) -> usize {
+        self.items
+            .iter()
+            .filter(|i| matches!(i.outcome(), Some(Outcome::Existing)))
+            .count()
+    }
+    pub fn bytes(&self) -> u64 {
+        self.items.iter().map(|i| i.bytes).sum()
+    }
+    pub fn failures(&self) -> impl Iterator<Item = &DownloadReport> {
+        self.items.iter().filter(|i| !i.is_ok())
+    }
 }
 
 impl OperationBuilder {
@@ -41,187 +196,536 @@ impl OperationBuilder {
     }
     pub fn concurrency(&mut self, n: usize) -> &mut Self {
         self.concurrency = Some(Arc::new(Semaphore::new(n)));
+        self.concurrency_limit = Some(Arc::new(AtomicUsize::new(n)));
         self
     }
+    /// Use a shared semaphore. Its available permits are taken as the
+    /// concurrency reported by `OperationControl`.
     pub fn with_semaphore(&mut self, sem: Arc<Semaphore>) -> &mut Self {
+        self.concurrency_limit = Some(Arc::new(AtomicUsize::new(sem.available_permits())));
         self.concurrency = Some(sem);
         self
     }
+    pub fn reporter<R>(&mut self, reporter: R) -> &mut Self
+    where
+        R: Reporter + 'static,
+    {
+        self.reporter = Some(Some(Arc::new(reporter)));
+        self
+    }
+    /// Call `f` with each `DownloadEvent`, in addition to the reporter.
+    pub fn observer<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&DownloadEvent) + Send + Sync + 'static,
+    {
+        self.observers
+            .get_or_insert_with(Vec::new)
+            .push(Arc::new(f));
+        self
+    }
+    /// Send each `DownloadEvent` down `tx`.
+    pub fn events(&mut self, tx: UnboundedSender<DownloadEvent>) -> &mut Self {
+        self.observers
+            .get_or_insert_with(Vec::new)
+            .push(channel_observer(tx));
+        self
+    }
+    /// Override `host_limits` for one host.
+    pub fn limit_host<S>(&mut self, host: S, limits: HostLimits) -> &mut Self
+    where
+        S: AsRef<str>,
+    {
+        self.per_host_limits
+            .get_or_insert_with(HashMap::new)
+            .insert(host.as_ref().to_ascii_lowercase(), limits);
+        self
+    }
 }
 
 impl Operation {
     pub fn builder() -> OperationBuilder {
         OperationBuilder::default()
     }
-    pub async fn run<S>(self, source: S) -> Result<(), Box<dyn Error>>
+    pub fn cancel_token(&self) -> &CancellationToken {
+        &self.cancel_token
+    }
+    /// A handle to pause, resume or cancel `run`, adjust its concurrency and
+    /// query its progress. Take it before calling `run`.
+    pub fn control(&self) -> OperationControl {
+        OperationControl {
+            gate: self.pause.clone(),
+            semaphore: self.concurrency.clone(),
+            limit: self.concurrency_limit.clone(),
+            debt: self.concurrency_debt.clone(),
+            token: self.cancel_token.clone(),
+            counters: self.counters.clone(),
+        }
+    }
+    fn default_reporter(&self) -> Arc<dyn Reporter> {
+        if !stderr().is_terminal() {
+            return Arc::new(LogReporter::default());
+        }
+        let mut b = ProgressReporter::builder();
+        if let Some(m) = &self.multiprogress {
+            b.multiprogress(m.clone());
+        }
+        if let Some(s) = &self.main_progress_style {
+            b.main_progress_style(s.clone());
+        }
+        if let Some(s) = &self.spin_progress_style {
+            b.spin_progress_style(s.clone());
+        }
+        if let Some(s) = &self.item_progress_style {
+            b.item_progress_style(s.clone());
+        }
+        if let Some(s) = &self.item_spinner_style {
+            b.item_spinner_style(s.clone());
+        }
+        if let Some(s) = &self.item_success_style {
+            b.item_success_style(s.clone());
+        }
+        if let Some(s) = &self.item_failure_style {
+            b.item_failure_style(s.clone());
+        }
+        Arc::new(b.build().unwrap())
+    }
+    pub async fn run<S>(self, source: S) -> Result<OperationReport, Error>
     where
         S: Source,
     {
-        let handles = Arc::new(RefCell::new(vec![]));
-        let mult = self
-            .multiprogress
-            .as_ref()
-            .cloned()
-            .unwrap_or_else(|| Arc::new(MultiProgress::new()));
-        let totalprogress = Arc::new(
-            mult.add(
-                ProgressBar::new(source.num_downloads()).with_style(
-                    self.main_progress_style
-                        .as_ref()
-                        .unwrap_or_else(|| main_progress_style())
-                        .clone(),
-                ),
-            ),
-        );
-        let spin_style = self
-            .spin_progress_style
-            .as_ref()
-            .unwrap_or_else(|| spin_progress_style());
-        let item_style = self
-            .item_progress_style
-            .as_ref()
-            .unwrap_or_else(|| item_progress_style());
-        let success_style = self
-            .item_success_style
-            .as_ref()
-            .unwrap_or_else(|| item_success_style());
-        let failure_style = self
-            .item_failure_style
-            .as_ref()
-            .unwrap_or_else(|| item_failure_style());
-
-        {
-            let handle_clone = handles.clone();
-            source
-                .apply_to_downloads(|file_dl| async {
-                    let ticket = self.concurrency.clone().acquire_owned().await?;
-                    let jh = spawn(create_task(
-                        ticket,
-                        self.client.clone(),
-                        file_dl,
-                        mult.clone(),
-                        totalprogress.clone(),
-                        spin_style.clone(),
-                        item_style.clone(),
-                        success_style.clone(),
-                        failure_style.clone(),
-                        self.wait_after_download,
-                    ));
-                    handle_clone.borrow_mut().push(jh);
-                    Ok(())
-                })
-                .await?;
-        }
-        for h in Arc::into_inner(handles).unwrap().take().into_iter() {
-            if let Err(e) = h.await {
-                mult.suspend(|| eprintln!("Error awaiting task: {e}"));
+        let mut reporters = vec![
+            self.reporter
+                .clone()
+                .unwrap_or_else(|| self.default_reporter()),
+            self.control().reporter(),
+        ];
+        if !self.observers.is_empty() {
+            reporters.push(Arc::new(EventReporter(self.observers.clone())));
+        }
+        let reporter: Arc<dyn Reporter> = Arc::new(Reporters(reporters));
+        reporter.begin(source.num_downloads());
+        let scheduler = Scheduler::new(self.host_limits.clone(), self.per_host_limits.clone());
+        let token = self.cancel_token.clone();
+        // cancelled by a second signal
+        let escalate = CancellationToken::new();
+        let abort = match self.cancel_policy {
+            CancelPolicy::Abort => token.clone(),
+            CancelPolicy::Finish => escalate.clone(),
+        };
+        let signals = self
+            .handle_signals
+            .then(|| spawn(cancel_on_signal(token.clone(), escalate.clone())));
+        let next_id = AtomicUsize::new(0);
+        let journaled = Mutex::new(vec![]);
+        let finite = source.num_downloads().is_some();
+        let produce = async {
+            let apply = source.apply_to_downloads(|mut file_dl| {
+                if file_dl.retry.is_none() {
+                    file_dl.retry.clone_from(&self.retry);
+                }
+                if let Some(l) = &self.rate_limit {
+                    file_dl.rate_limits.push(l.clone());
+                }
+                file_dl.pause = Some(self.pause.clone());
+                let id = next_id.fetch_add(1, Ordering::Relaxed);
+                reporter.queued(id, &file_dl);
+                if let Some(journal) = &self.journal {
+                    if let Some(path) = journal.completed(&file_dl) {
+                        reporter.started(id, &file_dl);
+                        let r = DownloadReport {
+                            download: file_dl,
+                            result: Ok((path, Outcome::Existing)),
+                            bytes: 0,
+                            duration: Duration::ZERO,
+                            attempts: 0,
+                        };
+                        reporter.finished(id, &r);
+                        journaled.lock().unwrap().push((id, r));
+                        return Either::Left(ready(Ok(())));
+                    }
+                    file_dl.resume = true;
+                }
+                Either::Right(scheduler.clone().push(id, file_dl).map(Ok))
+            });
+            // once cancelled, the scheduler is closed and keeps what is pushed
+            // as not attempted. A finite source is drained that way, but
+            // others may wait indefinitely for their next download
+            let res = if finite {
+                apply.await
+            } else {
+                until_cancelled(&token, apply).await.unwrap_or(Ok(()))
+            };
+            scheduler.close();
+            res
+        };
+        let dispatch = async {
+            let mut handles = vec![];
+            loop {
+                if until_cancelled(&token, self.pause.wait()).await.is_none() {
+                    break;
+                }
+                let ticket =
+                    match until_cancelled(&token, self.concurrency.clone().acquire_owned()).await {
+                        Some(Ok(t)) => t,
+                        Some(Err(_)) => {
+                            scheduler.close();
+                            return Err(Error::Closed);
+                        }
+                        None => break,
+                    };
+                // owed since the concurrency was lowered
+                if self
+                    .concurrency_debt
+                    .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |d| d.checked_sub(1))
+                    .is_ok()
+                {
+                    ticket.forget();
+                    continue;
+                }
+                let (id, file_dl, slot) = match until_cancelled(&token, scheduler.next()).await {
+                    Some(Some(next)) => next,
+                    // the source is exhausted
+                    Some(None) => return Ok(handles),
+                    None => break,
+                };
+                let jh = spawn(create_task(
+                    ticket,
+                    slot,
+                    self.client.clone(),
+                    id,
+                    file_dl.clone(),
+                    reporter.clone(),
+                    self.wait_after_download,
+                    token.clone(),
+                    abort.clone(),
+                    self.journal.clone(),
+                ));
+                handles.push((id, file_dl, jh));
             }
+            scheduler.close();
+            Ok(handles)
+        };
+        let (produced, handles) = join(produce, dispatch).await;
+        let handles = handles?;
+        let mut items = journaled.into_inner().unwrap();
+        for (id, file_dl, h) in handles {
+            let r = match h.await {
+                Ok(r) => r,
+                Err(e) => {
+                    let r = DownloadReport {
+                        download: file_dl,
+                        result: Err(e.into()),
+                        bytes: 0,
+                        duration: Duration::ZERO,
+                        attempts: 0,
+                    };
+                    reporter.finished(id, &r);
+                    r
+                }
+            };
+            items.push((id, r));
         }
-        totalprogress.finish();
-        Ok(())
+        // ids follow the source, while host limits may reorder dispatch
+        items.sort_by_key(|(id, _)| *id);
+        let mut report = OperationReport {
+            items: items.into_iter().map(|(_, r)| r).collect(),
+            ..Default::default()
+        };
+        if let Some(h) = signals {
+            h.abort();
+        }
+        report.cancelled = token.is_cancelled();
+        report.not_attempted = scheduler.drain();
+        reporter.end(&report);
+        produced?;
+        if self.fail_on_error && report.failed() > 0 {
+            return Err(Error::Incomplete(Box::new(report)));
+        }
+        Ok(report)
     }
 }
 
 pub trait Source {
-    fn num_downloads(&self) -> u64;
-    fn apply_to_downloads<F, R>(self, f: F) -> impl Future<Output = Result<(), Box<dyn Error>>>
+    /// How many downloads the source will yield, if known in advance. Such
+    /// a source is read to the end after cancellation, so should not block.
+    fn num_downloads(&self) -> Option<u64>;
+    fn apply_to_downloads<F, R>(self, f: F) -> impl Future<Output = Result<(), Error>>
     where
         F: Fn(FileDownload) -> R,
-        R: Future<Output = Result<(), Box<dyn Error>>>;
+        R: Future<Output = Result<(), Error>>;
 }
 
 impl Source for &[FileDownload] {
-    fn num_downloads(&self) -> u64 {
-        self.len() as u64
+    fn num_downloads(&self) -> Option<u64> {
+        Some(self.len() as u64)
     }
-    fn apply_to_downloads<F, R>(self, f: F) -> impl Future<Output = Result<(), Box<dyn Error>>>
+    async fn apply_to_downloads<F, R>(self, f: F) -> Result<(), Error>
     where
         F: Fn(FileDownload) -> R,
-        R: Future<Output = Result<(), Box<dyn Error>>>,
+        R: Future<Output = Result<(), Error>>,
     {
-        async move {
-            for i in self {
-                f(i.clone()).await?;
-            }
-            Ok(())
+        for i in self {
+            f(i.clone()).await?;
         }
+        Ok(())
     }
 }
 
+impl Source for Vec<FileDownload> {
+    fn num_downloads(&self) -> Option<u64> {
+        Some(self.len() as u64)
+    }
+    async fn apply_to_downloads<F, R>(self, f: F) -> Result<(), Error>
+    where
+        F: Fn(FileDownload) -> R,
+        R: Future<Output = Result<(), Error>>,
+    {
+        for i in self {
+            f(i).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Downloads received until every sender is dropped.
+impl Source for Receiver<FileDownload> {
+    fn num_downloads(&self) -> Option<u64> {
+        None
+    }
+    async fn apply_to_downloads<F, R>(mut self, f: F) -> Result<(), Error>
+    where
+        F: Fn(FileDownload) -> R,
+        R: Future<Output = Result<(), Error>>,
+    {
+        while let Some(i) = self.recv().await {
+            f(i).await?;
+        }
+        Ok(())
+    }
+}
+
+impl Source for UnboundedReceiver<FileDownload> {
+    fn num_downloads(&self) -> Option<u64> {
+        None
+    }
+    async fn apply_to_downloads<F, R>(mut self, f: F) -> Result<(), Error>
+    where
+        F: Fn(FileDownload) -> R,
+        R: Future<Output = Result<(), Error>>,
+    {
+        while let Some(i) = self.recv().await {
+            f(i).await?;
+        }
+        Ok(())
+    }
+}
+
+fn exact_len((lower, upper): (usize, Option<usize>)) -> Option<u64> {
+    (upper == Some(lower)).then_some(lower as u64)
+}
+
+/// A `Source` over any iterator of downloads, consumed lazily.
+pub struct IterSource<I>(pub I);
+
+impl<I> Source for IterSource<I>
+where
+    I: Iterator<Item = FileDownload>,
+{
+    fn num_downloads(&self) -> Option<u64> {
+        exact_len(self.0.size_hint())
+    }
+    async fn apply_to_downloads<F, R>(self, f: F) -> Result<(), Error>
+    where
+        F: Fn(FileDownload) -> R,
+        R: Future<Output = Result<(), Error>>,
+    {
+        for i in self.0 {
+            f(i).await?;
+        }
+        Ok(())
+    }
+}
+
+/// A `Source` over a `Stream` of downloads, for sources that discover
+/// downloads as they go.
+pub struct StreamSource<S>(pub S);
+
+impl<S> Source for StreamSource<S>
+where
+    S: Stream<Item = FileDownload>,
+{
+    fn num_downloads(&self) -> Option<u64> {
+        exact_len(self.0.size_hint())
+    }
+    async fn apply_to_downloads<F, R>(self, f: F) -> Result<(), Error>
+    where
+        F: Fn(FileDownload) -> R,
+        R: Future<Output = Result<(), Error>>,
+    {
+        let mut stream = pin!(self.0);
+        while let Some(i) = stream.next().await {
+            f(i).await?;
+        }
+        Ok(())
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn create_task(
     ticket: OwnedSemaphorePermit,
+    slot: HostSlot,
     client: Arc<Client>,
+    id: usize,
     file_dl: FileDownload,
-    mult: Arc<MultiProgress>,
-    totalprogress: Arc<ProgressBar>,
-    spin_style: ProgressStyle,
-    item_style: ProgressStyle,
-    success_style: ProgressStyle,
-    failure_style: ProgressStyle,
+    reporter: Arc<dyn Reporter>,
     wait_duration: Duration,
-) {
-    let spinner = mult.add(
-        ProgressBar::new_spinner()
-            .with_style(spin_style)
-            .with_message(
-                file_dl
-                    .title
-                    .as_ref()
-                    .cloned()
-                    .unwrap_or_else(|| "Setting up download".to_string()),
-            ),
+    token: CancellationToken,
+    abort: CancellationToken,
+    journal: Option<Journal>,
+) -> DownloadReport {
+    let start = Instant::now();
+    reporter.started(id, &file_dl);
+    if let Some(j) = &journal {
+        j.started(&file_dl);
+    }
+    // bytes received across attempts; `last_pos` is None until the current
+    // attempt reports its starting offset
+    let bytes = AtomicU64::new(0);
+    let last_pos: Mutex<Option<u64>> = Mutex::new(None);
+    let attempt = AtomicU32::new(1);
+    let download = file_dl.download_observed(
+        &client,
+        Some(|len, pos| {
+            if let Some(last) = last_pos.lock().unwrap().replace(pos) {
+                bytes.fetch_add(pos.saturating_sub(last), Ordering::Relaxed);
+            }
+            reporter.progress(id, len, pos);
+        }),
+        |n, e, delay| {
+            attempt.store(n + 1, Ordering::Relaxed);
+            last_pos.lock().unwrap().take();
+            reporter.retrying(id, n, e, delay);
+        },
+        |path, len| reporter.response(id, path, len),
     );
-    spinner.enable_steady_tick(Duration::from_millis(100));
-    let mut progress: Option<ProgressBar> = None;
-    let title = file_dl
-        .title
-        .as_ref()
-        .cloned()
-        .unwrap_or_else(|| file_dl.url.clone());
-    match file_dl
-        .download(
-            &client,
-            Some(|len, pos| {
-                if let Some(p) = &progress {
-                    p.set_position(pos);
-                } else {
-                    spinner.finish();
-                    let p =
-                        mult.insert_after(
-                            &spinner,
-                            ProgressBar::new(len)
-                                .with_message(file_dl.title.as_ref().cloned().unwrap_or_else(
-                                    || format!("Downloading from '{}'", &file_dl.url),
-                                ))
-                                .with_style(item_style.clone()),
-                        );
-                    mult.remove(&spinner);
-                    p.set_position(pos);
-                    progress.replace(p);
-                }
-            }),
-        )
+    let (attempts, res) = until_cancelled(&abort, download)
         .await
-    {
-        Ok(_) => {
-            if let Some(p) = progress {
-                p.set_style(success_style);
-                p.finish();
-            }
-        }
-        Err(e) => {
-            mult.suspend(|| {
-                eprintln!("Error downloading '{}': {e}", title);
-            });
-            if let Some(p) = progress {
-                p.set_style(failure_style);
-                p.finish();
-            }
-        }
+        .unwrap_or_else(|| (attempt.load(Ordering::Relaxed), Err(Error::Cancelled)));
+    let duration = start.elapsed();
+    drop(slot);
+    let report = DownloadReport {
+        download: file_dl,
+        result: res,
+        bytes: bytes.into_inner(),
+        duration,
+        attempts,
+    };
+    reporter.finished(id, &report);
+    if let Some(j) = &journal {
+        j.finished(&report);
     }
-    totalprogress.inc(1);
-    sleep(wait_duration).await;
+    until_cancelled(&token, sleep(wait_duration)).await;
     // we wanted to move here, so it is within this scope.
     // explicitly dropping does this for us
     drop(ticket);
+    report
+}
+
+async fn until_cancelled<F>(token: &CancellationToken, f: F) -> Option<F::Output>
+where
+    F: Future,
+{
+    match select(pin!(token.cancelled()), pin!(f)).await {
+        Either::Left(_) => None,
+        Either::Right((v, _)) => Some(v),
+    }
+}
+
+async fn cancel_on_signal(token: CancellationToken, escalate: CancellationToken) {
+    #[cfg(unix)]
+    let mut term = signal(SignalKind::terminate()).ok();
+    for n in 0.. {
+        let int = async {
+            if ctrl_c().await.is_err() {
+                pending::<()>().await;
+            }
+        };
+        #[cfg(unix)]
+        let term = async {
+            match &mut term {
+                Some(s) => {
+                    s.recv().await;
+                }
+                None => pending::<()>().await,
+            }
+        };
+        #[cfg(not(unix))]
+        let term = pending::<()>();
+        select(pin!(int), pin!(term)).await;
+        match n {
+            0 => {
+                log::warn!("Received signal, cancelling downloads...");
+                token.cancel();
+            }
+            1 => {
+                log::warn!("Received second signal, aborting running downloads...");
+                escalate.cancel();
+            }
+            _ => {
+                log::warn!("Received third signal, exiting");
+                std::process::exit(130);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::http::get_client;
+    use crate::test_util::{serve, Served};
+
+    fn downloads(url: &str, dir: &Path, n: usize) -> Vec<FileDownload> {
+        (0..n)
+            .map(|i| {
+                FileDownload::builder()
+                    .url(format!("{url}/f{i}"))
+                    .target(dir.to_owned())
+                    .filename(Some(format!("f{i}")))
+                    .title(None)
+                    .build()
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn cancel_reports_every_download() {
+        let mut served = Served::new(vec![0; 10]);
+        served.delay = Duration::from_millis(20);
+        let server = serve(served);
+        let dir = tempdir().unwrap();
+        let op = Operation::builder()
+            .client(get_client(None).unwrap())
+            .concurrency(2)
+            .wait_after_download(0)
+            .build()
+            .unwrap();
+        let control = op.control();
+        spawn(async move {
+            sleep(Duration::from_millis(200)).await;
+            control.cancel();
+        });
+        let report = op
+            .run(downloads(&server.url, dir.path(), 2000))
+            .await
+            .unwrap();
+        assert!(report.cancelled);
+        assert!(!report.items.is_empty());
+        assert_eq!(report.items.len() + report.not_attempted.len(), 2000);
+    }
 }
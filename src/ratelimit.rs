@@ -0,0 +1,89 @@
+use std::{
+    num::NonZeroU64,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::time::sleep;
+
+const MAX_WAIT: Duration = Duration::from_millis(100);
+
+#[derive(Debug)]
+struct Bucket {
+    rate: Option<NonZeroU64>,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self) {
+        let now = Instant::now();
+        if let Some(rate) = self.rate {
+            let rate = rate.get();
+            let elapsed = now.duration_since(self.last).as_secs_f64();
+            // allow up to one second's worth of burst
+            self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+        }
+        self.last = now;
+    }
+}
+
+/// A token bucket limiting throughput in bytes per second.
+///
+/// Clones share the same bucket, so one limiter can throttle many downloads
+/// and its rate can be changed with `set_rate` while they are running. A
+/// rate of 0 means no limit.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self::with_rate(Some(bytes_per_sec))
+    }
+    pub fn unlimited() -> Self {
+        Self::with_rate(None)
+    }
+    fn with_rate(rate: Option<u64>) -> Self {
+        Self {
+            bucket: Arc::new(Mutex::new(Bucket {
+                rate: rate.and_then(NonZeroU64::new),
+                tokens: 0.0,
+                last: Instant::now(),
+            })),
+        }
+    }
+    pub fn rate(&self) -> Option<u64> {
+        self.bucket.lock().unwrap().rate.map(NonZeroU64::get)
+    }
+    /// Change the rate; `None` or 0 removes the limit.
+    pub fn set_rate(&self, bytes_per_sec: Option<u64>) {
+        let mut b = self.bucket.lock().unwrap();
+        b.refill();
+        b.rate = bytes_per_sec.and_then(NonZeroU64::new);
+        if let Some(rate) = b.rate {
+            b.tokens = b.tokens.min(rate.get() as f64);
+        }
+    }
+    /// Take `bytes` from the bucket, first waiting for any debt left by
+    /// earlier callers to be repaid. Waits are short so that rate changes
+    /// take effect promptly.
+    pub async fn acquire(&self, bytes: u64) {
+        loop {
+            let wait = {
+                let mut b = self.bucket.lock().unwrap();
+                b.refill();
+                match b.rate {
+                    None => return,
+                    Some(_) if b.tokens >= 0.0 => {
+                        b.tokens -= bytes as f64;
+                        return;
+                    }
+                    Some(rate) => Duration::from_secs_f64(-b.tokens / rate.get() as f64),
+                }
+            };
+            sleep(wait.min(MAX_WAIT)).await;
+        }
+    }
+}
@@ -0,0 +1,484 @@
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use derive_builder::Builder;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use serde_json::{json, Value};
+
+use crate::http::FileDownload;
+use crate::operation::{DownloadReport, OperationReport};
+use crate::style::*;
+use crate::Error;
+
+/// Receives the progress of an `Operation`.
+///
+/// `id` numbers the downloads of a run in the order the source yielded them.
+/// Methods are called from the download tasks and should not block for long.
+pub trait Reporter: Send + Sync {
+    /// A run is starting with `total` downloads, if the source knows.
+    fn begin(&self, _total: Option<u64>) {}
+    /// The download was taken from the source and is waiting to be dispatched.
+    fn queued(&self, _id: usize, _download: &FileDownload) {}
+    fn started(&self, _id: usize, _download: &FileDownload) {}
+    /// Response headers arrived, resolving the target path and length.
+    fn response(&self, _id: usize, _path: &Path, _len: Option<u64>) {}
+    /// Bytes of the current attempt, as passed to `FileDownload::download`.
+    fn progress(&self, _id: usize, _len: Option<u64>, _pos: u64) {}
+    /// Attempt `attempt` failed and will be retried after `delay`.
+    fn retrying(&self, _id: usize, _attempt: u32, _error: &Error, _delay: Duration) {}
+    fn finished(&self, _id: usize, _report: &DownloadReport) {}
+    fn end(&self, _report: &OperationReport) {}
+}
+
+fn title(download: &FileDownload) -> String {
+    download
+        .title
+        .as_ref()
+        .cloned()
+        .unwrap_or_else(|| download.url.clone())
+}
+
+struct Item {
+    spinner: ProgressBar,
+    progress: Option<ProgressBar>,
+    message: String,
+    attempt: u32,
+    max_attempts: u32,
+}
+
+impl Item {
+    fn message(&self) -> String {
+        if self.attempt > 1 {
+            format!(
+                "{} (attempt {}/{})",
+                self.message, self.attempt, self.max_attempts
+            )
+        } else {
+            self.message.clone()
+        }
+    }
+}
+
+/// Draws a bar for the whole operation and one per running download.
+#[derive(Default, Builder)]
+pub struct ProgressReporter {
+    #[builder(default, setter(into, strip_option))]
+    multiprogress: Option<Arc<MultiProgress>>,
+    #[builder(default, setter(into, strip_option))]
+    main_progress_style: Option<ProgressStyle>,
+    #[builder(default, setter(into, strip_option))]
+    spin_progress_style: Option<ProgressStyle>,
+    #[builder(default, setter(into, strip_option))]
+    item_progress_style: Option<ProgressStyle>,
+    #[builder(default, setter(into, strip_option))]
+    item_spinner_style: Option<ProgressStyle>,
+    #[builder(default, setter(into, strip_option))]
+    item_success_style: Option<ProgressStyle>,
+    #[builder(default, setter(into, strip_option))]
+    item_failure_style: Option<ProgressStyle>,
+    #[builder(setter(skip))]
+    state: Mutex<ProgressState>,
+}
+
+#[derive(Default)]
+struct ProgressState {
+    mult: Option<Arc<MultiProgress>>,
+    total: Option<ProgressBar>,
+    // the length of `total` follows the queued downloads
+    growing: bool,
+    items: HashMap<usize, Item>,
+}
+
+impl ProgressReporter {
+    pub fn builder() -> ProgressReporterBuilder {
+        ProgressReporterBuilder::default()
+    }
+    fn mult(&self) -> Arc<MultiProgress> {
+        let mut state = self.state.lock().unwrap();
+        state
+            .mult
+            .get_or_insert_with(|| {
+                self.multiprogress
+                    .clone()
+                    .unwrap_or_else(|| Arc::new(MultiProgress::new()))
+            })
+            .clone()
+    }
+}
+
+impl Reporter for ProgressReporter {
+    fn begin(&self, total: Option<u64>) {
+        let mult = self.mult();
+        let bar = mult.add(
+            ProgressBar::new(total.unwrap_or(0)).with_style(
+                self.main_progress_style
+                    .as_ref()
+                    .unwrap_or_else(|| main_progress_style())
+                    .clone(),
+            ),
+        );
+        let mut state = self.state.lock().unwrap();
+        state.total = Some(bar);
+        state.growing = total.is_none();
+    }
+    fn queued(&self, _id: usize, _download: &FileDownload) {
+        let state = self.state.lock().unwrap();
+        if let (true, Some(t)) = (state.growing, state.total.as_ref()) {
+            t.inc_length(1);
+        }
+    }
+    fn started(&self, id: usize, download: &FileDownload) {
+        let mult = self.mult();
+        let spinner = mult.add(
+            ProgressBar::new_spinner()
+                .with_style(
+                    self.spin_progress_style
+                        .as_ref()
+                        .unwrap_or_else(|| spin_progress_style())
+                        .clone(),
+                )
+                .with_message(
+                    download
+                        .title
+                        .as_ref()
+                        .cloned()
+                        .unwrap_or_else(|| "Setting up download".to_string()),
+                ),
+        );
+        spinner.enable_steady_tick(Duration::from_millis(100));
+        let message = download
+            .title
+            .as_ref()
+            .cloned()
+            .unwrap_or_else(|| format!("Downloading from '{}'", &download.url));
+        let item = Item {
+            spinner,
+            progress: None,
+            message,
+            attempt: 1,
+            max_attempts: download.retry.as_ref().map_or(1, |r| r.max_attempts()),
+        };
+        self.state.lock().unwrap().items.insert(id, item);
+    }
+    fn progress(&self, id: usize, len: Option<u64>, pos: u64) {
+        let mut state = self.state.lock().unwrap();
+        let ProgressState { mult, items, .. } = &mut *state;
+        let (Some(mult), Some(item)) = (mult, items.get_mut(&id)) else {
+            return;
+        };
+        if let Some(p) = item.progress.as_ref() {
+            if let Some(len) = len {
+                p.set_length(len);
+            }
+            p.set_position(pos);
+            return;
+        }
+        item.spinner.finish();
+        let bar = match len {
+            Some(len) => ProgressBar::new(len).with_style(
+                self.item_progress_style
+                    .as_ref()
+                    .unwrap_or_else(|| item_progress_style())
+                    .clone(),
+            ),
+            None => {
+                let bar = ProgressBar::new_spinner().with_style(
+                    self.item_spinner_style
+                        .as_ref()
+                        .unwrap_or_else(|| item_spinner_style())
+                        .clone(),
+                );
+                bar.enable_steady_tick(Duration::from_millis(100));
+                bar
+            }
+        };
+        let p = mult.insert_after(&item.spinner, bar.with_message(item.message()));
+        mult.remove(&item.spinner);
+        p.set_position(pos);
+        item.progress = Some(p);
+    }
+    fn retrying(&self, id: usize, attempt: u32, _error: &Error, _delay: Duration) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(item) = state.items.get_mut(&id) {
+            item.attempt = attempt + 1;
+            match item.progress.as_ref() {
+                Some(p) => p.set_message(item.message()),
+                None => item.spinner.set_message(item.message()),
+            }
+        }
+    }
+    fn finished(&self, id: usize, report: &DownloadReport) {
+        let mut state = self.state.lock().unwrap();
+        let item = state.items.remove(&id);
+        if let Some(p) = item.as_ref().and_then(|i| i.progress.as_ref()) {
+            if p.length().is_none() {
+                // so the finished styles show the bytes received
+                p.set_length(p.position());
+            }
+            let style = match report.result {
+                Ok(_) => self
+                    .item_success_style
+                    .as_ref()
+                    .unwrap_or_else(|| item_success_style()),
+                Err(_) => self
+                    .item_failure_style
+                    .as_ref()
+                    .unwrap_or_else(|| item_failure_style()),
+            };
+            p.set_style(style.clone());
+            p.finish();
+        } else if let Some(i) = item.as_ref() {
+            i.spinner.finish_and_clear();
+        }
+        if let (Err(e), Some(mult)) = (&report.result, state.mult.as_ref()) {
+            let title = title(&report.download);
+            mult.suspend(|| {
+                if report.attempts > 1 {
+                    eprintln!(
+                        "Error downloading '{}' after {} attempts: {e}",
+                        title, report.attempts
+                    );
+                } else {
+                    eprintln!("Error downloading '{}': {e}", title);
+                }
+            });
+        }
+        if let Some(t) = state.total.as_ref() {
+            t.inc(1);
+        }
+    }
+    fn end(&self, _report: &OperationReport) {
+        if let Some(t) = self.state.lock().unwrap().total.take() {
+            t.finish();
+        }
+    }
+}
+
+// rate-limits progress events per download
+#[derive(Default)]
+struct Checkpoints {
+    interval: Duration,
+    last: Mutex<HashMap<usize, Instant>>,
+}
+
+impl Checkpoints {
+    fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last: Mutex::default(),
+        }
+    }
+    fn due(&self, id: usize) -> bool {
+        let now = Instant::now();
+        let mut last = self.last.lock().unwrap();
+        match last.get(&id) {
+            Some(t) if now.duration_since(*t) < self.interval => false,
+            _ => {
+                last.insert(id, now);
+                true
+            }
+        }
+    }
+    fn forget(&self, id: usize) {
+        self.last.lock().unwrap().remove(&id);
+    }
+}
+
+/// One line per event through the `log` crate. Progress is logged at debug
+/// level, at most once per `interval` per download.
+pub struct LogReporter {
+    checkpoints: Checkpoints,
+}
+
+impl Default for LogReporter {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(10))
+    }
+}
+
+impl LogReporter {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            checkpoints: Checkpoints::new(interval),
+        }
+    }
+}
+
+impl Reporter for LogReporter {
+    fn begin(&self, total: Option<u64>) {
+        match total {
+            Some(total) => log::info!("Starting {total} downloads"),
+            None => log::info!("Starting downloads"),
+        }
+    }
+    fn started(&self, _id: usize, download: &FileDownload) {
+        log::info!("Downloading '{}'", download.url);
+    }
+    fn progress(&self, id: usize, len: Option<u64>, pos: u64) {
+        if !log::log_enabled!(log::Level::Debug) || !self.checkpoints.due(id) {
+            return;
+        }
+        match len {
+            Some(len) => log::debug!("Download {id}: {pos}/{len} bytes"),
+            None => log::debug!("Download {id}: {pos} bytes"),
+        }
+    }
+    fn finished(&self, id: usize, report: &DownloadReport) {
+        self.checkpoints.forget(id);
+        match &report.result {
+            Ok((path, outcome)) => log::info!(
+                "Finished '{}' -> {} ({}, {} bytes in {:?})",
+                report.download.url,
+                path.display(),
+                <&str>::from(outcome),
+                report.bytes,
+                report.duration
+            ),
+            Err(e) => log::error!(
+                "Failed '{}' after {} attempts: {e}",
+                report.download.url,
+                report.attempts
+            ),
+        }
+    }
+    fn end(&self, report: &OperationReport) {
+        log::info!(
+            "Downloaded {} of {} items ({} bytes), {} failed",
+            report.succeeded(),
+            report.total(),
+            report.bytes(),
+            report.failed()
+        );
+    }
+}
+
+/// Writes one JSON object per event, e.g.
+/// `{"event":"finished","id":3,"path":"...","outcome":"download",...}`.
+pub struct JsonReporter {
+    out: Mutex<Box<dyn Write + Send>>,
+    checkpoints: Checkpoints,
+}
+
+impl JsonReporter {
+    pub fn new<W>(out: W) -> Self
+    where
+        W: Write + Send + 'static,
+    {
+        Self {
+            out: Mutex::new(Box::new(out)),
+            checkpoints: Checkpoints::new(Duration::from_secs(1)),
+        }
+    }
+    pub fn stderr() -> Self {
+        Self::new(io::stderr())
+    }
+    /// Emit progress at most once per `interval` per download.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.checkpoints = Checkpoints::new(interval);
+        self
+    }
+    fn emit(&self, event: Value) {
+        let mut out = self.out.lock().unwrap();
+        // reporting must not fail the download
+        let _ = writeln!(out, "{event}").and_then(|_| out.flush());
+    }
+}
+
+impl Reporter for JsonReporter {
+    fn begin(&self, total: Option<u64>) {
+        self.emit(json!({"event": "begin", "total": total}));
+    }
+    fn started(&self, id: usize, download: &FileDownload) {
+        self.emit(json!({
+            "event": "started",
+            "id": id,
+            "url": download.url,
+            "title": download.title,
+        }));
+    }
+    fn progress(&self, id: usize, len: Option<u64>, pos: u64) {
+        if self.checkpoints.due(id) {
+            self.emit(json!({"event": "progress", "id": id, "bytes": pos, "total": len}));
+        }
+    }
+    fn retrying(&self, id: usize, attempt: u32, error: &Error, delay: Duration) {
+        self.emit(json!({
+            "event": "retrying",
+            "id": id,
+            "attempt": attempt,
+            "error": error.to_string(),
+            "delay_ms": delay.as_millis() as u64,
+        }));
+    }
+    fn finished(&self, id: usize, report: &DownloadReport) {
+        self.checkpoints.forget(id);
+        let event = match &report.result {
+            Ok((path, outcome)) => json!({
+                "event": "finished",
+                "id": id,
+                "url": report.download.url,
+                "path": path,
+                "outcome": <&str>::from(outcome),
+                "bytes": report.bytes,
+                "duration_ms": report.duration.as_millis() as u64,
+                "attempts": report.attempts,
+            }),
+            Err(e) => json!({
+                "event": "failed",
+                "id": id,
+                "url": report.download.url,
+                "error": e.to_string(),
+                "bytes": report.bytes,
+                "duration_ms": report.duration.as_millis() as u64,
+                "attempts": report.attempts,
+            }),
+        };
+        self.emit(event);
+    }
+    fn end(&self, report: &OperationReport) {
+        self.emit(json!({
+            "event": "end",
+            "total": report.total(),
+            "succeeded": report.succeeded(),
+            "failed": report.failed(),
+            "bytes": report.bytes(),
+        }));
+    }
+}
+
+/// Forwards to each reporter in turn.
+pub(crate) struct Reporters(pub(crate) Vec<Arc<dyn Reporter>>);
+
+impl Reporter for Reporters {
+    fn begin(&self, total: Option<u64>) {
+        self.0.iter().for_each(|r| r.begin(total));
+    }
+    fn queued(&self, id: usize, download: &FileDownload) {
+        self.0.iter().for_each(|r| r.queued(id, download));
+    }
+    fn started(&self, id: usize, download: &FileDownload) {
+        self.0.iter().for_each(|r| r.started(id, download));
+    }
+    fn response(&self, id: usize, path: &Path, len: Option<u64>) {
+        self.0.iter().for_each(|r| r.response(id, path, len));
+    }
+    fn progress(&self, id: usize, len: Option<u64>, pos: u64) {
+        self.0.iter().for_each(|r| r.progress(id, len, pos));
+    }
+    fn retrying(&self, id: usize, attempt: u32, error: &Error, delay: Duration) {
+        self.0
+            .iter()
+            .for_each(|r| r.retrying(id, attempt, error, delay));
+    }
+    fn finished(&self, id: usize, report: &DownloadReport) {
+        self.0.iter().for_each(|r| r.finished(id, report));
+    }
+    fn end(&self, report: &OperationReport) {
+        self.0.iter().for_each(|r| r.end(report));
+    }
+}
@@ -0,0 +1,251 @@
+use std::time::{Duration, SystemTime};
+
+use derive_builder::Builder;
+use rand::{thread_rng, Rng};
+use reqwest::{
+    header::{HeaderMap, RETRY_AFTER},
+    StatusCode,
+};
+
+use crate::Error;
+
+/// Classes of transport failure that a `RetryPolicy` may retry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// Could not establish a connection.
+    Connect,
+    /// The request or response body timed out.
+    Timeout,
+    /// The connection failed while streaming the response body.
+    Body,
+    /// Any other error sending the request.
+    Request,
+}
+
+impl ErrorKind {
+    pub(crate) fn of(e: &reqwest::Error) -> Option<Self> {
+        if e.is_timeout() {
+            Some(Self::Timeout)
+        } else if e.is_connect() {
+            Some(Self::Connect)
+        } else if e.is_body() || e.is_decode() {
+            Some(Self::Body)
+        } else if e.is_request() {
+            Some(Self::Request)
+        } else {
+            None
+        }
+    }
+}
+
+pub(crate) const DEFAULT_STATUSES: [StatusCode; 6] = [
+    StatusCode::REQUEST_TIMEOUT,
+    StatusCode::TOO_MANY_REQUESTS,
+    StatusCode::INTERNAL_SERVER_ERROR,
+    StatusCode::BAD_GATEWAY,
+    StatusCode::SERVICE_UNAVAILABLE,
+    StatusCode::GATEWAY_TIMEOUT,
+];
+
+fn default_error_kinds() -> Vec<ErrorKind> {
+    vec![
+        ErrorKind::Connect,
+        ErrorKind::Timeout,
+        ErrorKind::Body,
+        ErrorKind::Request,
+    ]
+}
+
+/// Exponential backoff for failed downloads.
+///
+/// The delay before attempt `n + 1` is `base_delay * 2^(n - 1)`, capped at
+/// `max_delay`. With `jitter` the delay is drawn uniformly from the upper half
+/// of that range. A `Retry-After` header on a retryable status takes
+/// precedence when it asks for a longer wait.
+#[derive(Debug, Clone, Builder)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first.
+    #[builder(default = "3")]
+    max_attempts: u32,
+    #[builder(default = "Duration::from_secs(1)")]
+    base_delay: Duration,
+    #[builder(default = "Duration::from_secs(60)")]
+    max_delay: Duration,
+    #[builder(default = "true")]
+    jitter: bool,
+    #[builder(default = "DEFAULT_STATUSES.to_vec()", setter(into))]
+    statuses: Vec<StatusCode>,
+    #[builder(default = "default_error_kinds()", setter(into))]
+    error_kinds: Vec<ErrorKind>,
+    #[builder(default = "true")]
+    honour_retry_after: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::builder().build().unwrap()
+    }
+}
+
+impl RetryPolicy {
+    pub fn builder() -> RetryPolicyBuilder {
+        RetryPolicyBuilder::default()
+    }
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+    fn backoff(&self, attempt: u32) -> Duration {
+        let exp = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_delay);
+        if self.jitter {
+            thread_rng().gen_range(exp / 2..=exp)
+        } else {
+            exp
+        }
+    }
+    /// How long to wait before retrying after `attempt` attempts have failed
+    /// with `err`, or `None` if the error is not retryable or attempts are
+    /// exhausted.
+    pub fn retry_delay(&self, attempt: u32, err: &Error) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+        if let Some(status) = err.status() {
+            if !self.statuses.contains(&status) {
+                return None;
+            }
+            let backoff = self.backoff(attempt);
+            return Some(match err.retry_after() {
+                Some(ra) if self.honour_retry_after => ra.max(backoff),
+                _ => backoff,
+            });
+        }
+        self.error_kinds
+            .contains(&err.transport_kind()?)
+            .then(|| self.backoff(attempt))
+    }
+}
+
+/// Parse a `Retry-After` header given either in seconds or as an HTTP date.
+pub(crate) fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let v = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = v.parse() {
+        return Some(Duration::from_secs(secs));
+    }
+    httpdate::parse_http_date(v)
+        .ok()?
+        .duration_since(SystemTime::now())
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> RetryPolicyBuilder {
+        let mut b = RetryPolicy::builder();
+        b.base_delay(Duration::from_secs(1))
+            .max_delay(Duration::from_secs(10))
+            .jitter(false);
+        b
+    }
+
+    fn status(code: u16, retry_after: Option<u64>) -> Error {
+        Error::HttpStatus {
+            url: "http://a.test/".to_string(),
+            status: StatusCode::from_u16(code).unwrap(),
+            retry_after: retry_after.map(Duration::from_secs),
+            preflight: false,
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let p = policy().build().unwrap();
+        let delays: Vec<_> = [1, 2, 3, 4, 5, u32::MAX]
+            .into_iter()
+            .map(|n| p.backoff(n).as_secs())
+            .collect();
+        assert_eq!(delays, [1, 2, 4, 8, 10, 10]);
+    }
+
+    #[test]
+    fn jitter_in_upper_half() {
+        let p = policy().jitter(true).build().unwrap();
+        for _ in 0..100 {
+            let d = p.backoff(3);
+            assert!(d >= Duration::from_secs(2) && d <= Duration::from_secs(4));
+        }
+    }
+
+    #[test]
+    fn attempts_are_capped() {
+        let p = policy().max_attempts(3u32).build().unwrap();
+        let e = status(503, None);
+        assert_eq!(p.retry_delay(2, &e), Some(Duration::from_secs(2)));
+        assert_eq!(p.retry_delay(3, &e), None);
+        let once = policy().max_attempts(1u32).build().unwrap();
+        assert_eq!(once.retry_delay(1, &e), None);
+    }
+
+    #[test]
+    fn statuses_are_filtered() {
+        let p = policy().build().unwrap();
+        assert!(p.retry_delay(1, &status(429, None)).is_some());
+        assert!(p.retry_delay(1, &status(404, None)).is_none());
+        assert!(p.retry_delay(1, &Error::Cancelled).is_none());
+        let p = policy()
+            .statuses(vec![StatusCode::NOT_FOUND])
+            .build()
+            .unwrap();
+        assert!(p.retry_delay(1, &status(404, None)).is_some());
+        assert!(p.retry_delay(1, &status(503, None)).is_none());
+    }
+
+    #[test]
+    fn retry_after_when_longer() {
+        let p = policy().build().unwrap();
+        let secs = |attempt, e| p.retry_delay(attempt, &e).unwrap().as_secs();
+        assert_eq!(secs(1, status(503, Some(30))), 30);
+        // more than `max_delay`, as the server asked
+        assert_eq!(secs(1, status(429, Some(120))), 120);
+        assert_eq!(secs(2, status(503, Some(1))), 2);
+        let p = policy().honour_retry_after(false).build().unwrap();
+        assert_eq!(
+            p.retry_delay(1, &status(503, Some(30))),
+            Some(Duration::from_secs(1))
+        );
+    }
+
+    #[tokio::test]
+    async fn error_kinds_are_filtered() {
+        // nothing listens on port 1
+        let source = reqwest::get("http://127.0.0.1:1/").await.unwrap_err();
+        let e = Error::Request {
+            url: "http://127.0.0.1:1/".to_string(),
+            source,
+        };
+        assert_eq!(e.transport_kind(), Some(ErrorKind::Connect));
+        assert!(policy().build().unwrap().retry_delay(1, &e).is_some());
+        let p = policy()
+            .error_kinds(vec![ErrorKind::Timeout])
+            .build()
+            .unwrap();
+        assert!(p.retry_delay(1, &e).is_none());
+    }
+
+    #[test]
+    fn parse_retry_after() {
+        let mut h = HeaderMap::new();
+        h.insert(RETRY_AFTER, "120".parse().unwrap());
+        assert_eq!(retry_after(&h), Some(Duration::from_secs(120)));
+        let later = SystemTime::now() + Duration::from_secs(600);
+        h.insert(RETRY_AFTER, httpdate::fmt_http_date(later).parse().unwrap());
+        let d = retry_after(&h).unwrap();
+        assert!(d > Duration::from_secs(590) && d <= Duration::from_secs(600));
+        h.insert(RETRY_AFTER, "soon".parse().unwrap());
+        assert_eq!(retry_after(&h), None);
+    }
+}
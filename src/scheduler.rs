@@ -0,0 +1,301 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    pin::pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use derive_builder::Builder;
+use reqwest::Url;
+use tokio::{sync::Notify, time::timeout};
+
+use crate::http::FileDownload;
+
+// how many downloads a source may queue ahead of dispatch
+const QUEUE_LIMIT: usize = 1024;
+
+/// Politeness limits applied to each host separately.
+#[derive(Debug, Clone, Default, Builder)]
+pub struct HostLimits {
+    /// Maximum simultaneous downloads from the host.
+    #[builder(default, setter(strip_option))]
+    max_concurrent: Option<usize>,
+    /// Minimum time between starting downloads from the host.
+    #[builder(default, setter(strip_option))]
+    min_interval: Option<Duration>,
+}
+
+impl HostLimitsBuilder {
+    /// Sets `min_interval` to `1 / rps` seconds. Zero, negative and NaN
+    /// rates, like a rate of 0 for `RateLimiter`, mean no limit.
+    pub fn requests_per_second(&mut self, rps: f64) -> &mut Self {
+        self.min_interval = Some(
+            (rps > 0.0).then(|| Duration::try_from_secs_f64(1.0 / rps).unwrap_or(Duration::MAX)),
+        );
+        self
+    }
+}
+
+impl HostLimits {
+    pub fn builder() -> HostLimitsBuilder {
+        HostLimitsBuilder::default()
+    }
+}
+
+pub(crate) fn host_of(url: &str) -> String {
+    Url::parse(url)
+        .ok()
+        .and_then(|u| u.host_str().map(str::to_ascii_lowercase))
+        .unwrap_or_default()
+}
+
+#[derive(Default)]
+struct HostState {
+    active: usize,
+    next_start: Option<Instant>,
+}
+
+#[derive(Default)]
+struct State {
+    queue: VecDeque<(String, usize, FileDownload)>,
+    hosts: HashMap<String, HostState>,
+    closed: bool,
+    // pushed after `close`
+    rejected: Vec<FileDownload>,
+}
+
+/// Queue of pending downloads that hands out the first one whose host is
+/// ready, so a host that is cooling down does not hold up the others.
+pub(crate) struct Scheduler {
+    default: HostLimits,
+    per_host: HashMap<String, HostLimits>,
+    state: Mutex<State>,
+    notify: Notify,
+}
+
+/// Held while a download runs; frees the host's slot when dropped.
+pub(crate) struct HostSlot {
+    scheduler: Arc<Scheduler>,
+    host: String,
+}
+
+impl Drop for HostSlot {
+    fn drop(&mut self) {
+        if let Some(h) = self
+            .scheduler
+            .state
+            .lock()
+            .unwrap()
+            .hosts
+            .get_mut(&self.host)
+        {
+            h.active -= 1;
+        }
+        self.scheduler.notify.notify_waiters();
+    }
+}
+
+impl Scheduler {
+    pub(crate) fn new(default: HostLimits, per_host: HashMap<String, HostLimits>) -> Arc<Self> {
+        Arc::new(Self {
+            default,
+            per_host,
+            state: Mutex::new(State::default()),
+            notify: Notify::new(),
+        })
+    }
+    fn limits(&self, host: &str) -> &HostLimits {
+        self.per_host.get(host).unwrap_or(&self.default)
+    }
+    /// Queue a download, waiting while the queue is full. Once closed, the
+    /// download is kept for `drain` instead.
+    ///
+    /// Takes an `Arc` so the future borrows nothing, which keeps
+    /// `Operation::run` spawnable (rust-lang/rust#110338).
+    pub(crate) async fn push(self: Arc<Self>, id: usize, file_dl: FileDownload) {
+        let host = host_of(&file_dl.url);
+        loop {
+            let mut notified = pin!(self.notify.notified());
+            notified.as_mut().enable();
+            {
+                let mut st = self.state.lock().unwrap();
+                if st.closed {
+                    st.rejected.push(file_dl);
+                    return;
+                }
+                if st.queue.len() < QUEUE_LIMIT {
+                    st.queue.push_back((host, id, file_dl));
+                    drop(st);
+                    self.notify.notify_waiters();
+                    return;
+                }
+            }
+            notified.await;
+        }
+    }
+    /// No more downloads will be pushed.
+    pub(crate) fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.notify.notify_waiters();
+    }
+    /// Take every download that was pushed but not dispatched.
+    pub(crate) fn drain(&self) -> Vec<FileDownload> {
+        let mut st = self.state.lock().unwrap();
+        let mut v: Vec<_> = st.queue.drain(..).map(|(.., file_dl)| file_dl).collect();
+        v.append(&mut st.rejected);
+        v
+    }
+    /// The next download that may start, or `None` once closed and drained.
+    pub(crate) async fn next(self: &Arc<Self>) -> Option<(usize, FileDownload, HostSlot)> {
+        loop {
+            let mut notified = pin!(self.notify.notified());
+            notified.as_mut().enable();
+            let wake = {
+                let mut st = self.state.lock().unwrap();
+                let now = Instant::now();
+                let mut wake: Option<Instant> = None;
+                let mut ready = None;
+                for (i, (host, ..)) in st.queue.iter().enumerate() {
+                    let limits = self.limits(host);
+                    let hs = st.hosts.get(host);
+                    if limits
+                        .max_concurrent
+                        .is_some_and(|m| hs.map_or(0, |h| h.active) >= m)
+                    {
+                        continue;
+                    }
+                    match hs.and_then(|h| h.next_start) {
+                        Some(t) if t > now => wake = Some(wake.map_or(t, |w| w.min(t))),
+                        _ => {
+                            ready = Some(i);
+                            break;
+                        }
+                    }
+                }
+                if let Some((host, id, file_dl)) = ready.and_then(|i| st.queue.remove(i)) {
+                    let hs = st.hosts.entry(host.clone()).or_default();
+                    hs.active += 1;
+                    hs.next_start = self
+                        .limits(&host)
+                        .min_interval
+                        .and_then(|d| now.checked_add(d));
+                    drop(st);
+                    // there is room in the queue again
+                    self.notify.notify_waiters();
+                    let slot = HostSlot {
+                        scheduler: self.clone(),
+                        host,
+                    };
+                    return Some((id, file_dl, slot));
+                }
+                if st.queue.is_empty() && st.closed {
+                    return None;
+                }
+                wake
+            };
+            match wake {
+                Some(t) => {
+                    let _ = timeout(t.saturating_duration_since(Instant::now()), notified).await;
+                }
+                None => notified.await,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dl(url: &str) -> FileDownload {
+        FileDownload::builder()
+            .url(url)
+            .target("dl")
+            .title(None)
+            .build()
+            .unwrap()
+    }
+
+    async fn next_id(s: &Arc<Scheduler>) -> (usize, HostSlot) {
+        let (id, _, slot) = s.next().await.unwrap();
+        (id, slot)
+    }
+
+    async fn pending(s: &Arc<Scheduler>) -> bool {
+        timeout(Duration::from_millis(50), s.next()).await.is_err()
+    }
+
+    #[test]
+    fn requests_per_second() {
+        let l = HostLimits::builder()
+            .requests_per_second(4.0)
+            .build()
+            .unwrap();
+        assert_eq!(l.min_interval, Some(Duration::from_millis(250)));
+        for rps in [0.0, -1.0, f64::NAN] {
+            let l = HostLimits::builder()
+                .requests_per_second(rps)
+                .build()
+                .unwrap();
+            assert_eq!(l.min_interval, None);
+        }
+    }
+
+    #[tokio::test]
+    async fn max_concurrent_skips_busy_host() {
+        let limits = HostLimits::builder().max_concurrent(1).build().unwrap();
+        let s = Scheduler::new(limits, HashMap::new());
+        for (id, url) in ["http://a.test/1", "http://a.test/2", "http://b.test/1"]
+            .into_iter()
+            .enumerate()
+        {
+            s.clone().push(id, dl(url)).await;
+        }
+        let (first, a) = next_id(&s).await;
+        let (second, b) = next_id(&s).await;
+        assert_eq!((first, second), (0, 2));
+        assert!(pending(&s).await);
+        drop(b);
+        assert!(pending(&s).await);
+        drop(a);
+        assert_eq!(next_id(&s).await.0, 1);
+    }
+
+    #[tokio::test]
+    async fn min_interval_per_host() {
+        let interval = Duration::from_millis(200);
+        let mut per_host = HashMap::new();
+        per_host.insert(
+            "a.test".to_string(),
+            HostLimits::builder()
+                .min_interval(interval)
+                .build()
+                .unwrap(),
+        );
+        let s = Scheduler::new(HostLimits::default(), per_host);
+        for (id, url) in ["http://a.test/1", "http://a.test/2", "http://b.test/1"]
+            .into_iter()
+            .enumerate()
+        {
+            s.clone().push(id, dl(url)).await;
+        }
+        let start = Instant::now();
+        // slots are dropped at once: the interval is between starts
+        assert_eq!(next_id(&s).await.0, 0);
+        assert_eq!(next_id(&s).await.0, 2);
+        assert!(start.elapsed() < interval);
+        assert_eq!(next_id(&s).await.0, 1);
+        assert!(start.elapsed() >= interval);
+    }
+
+    #[tokio::test]
+    async fn close_keeps_pushed_downloads() {
+        let s = Scheduler::new(HostLimits::default(), HashMap::new());
+        s.clone().push(0, dl("http://a.test/1")).await;
+        s.close();
+        s.clone().push(1, dl("http://a.test/2")).await;
+        let urls: Vec<_> = s.drain().into_iter().map(|d| d.url).collect();
+        assert_eq!(urls, ["http://a.test/1", "http://a.test/2"]);
+        assert!(s.next().await.is_none());
+    }
+}
@@ -29,6 +29,17 @@ pub fn item_progress_style() -> &'static ProgressStyle {
     )
 }
 
+pub fn item_spinner_style() -> &'static ProgressStyle {
+    static MEM: OnceLock<ProgressStyle> = OnceLock::new();
+    MEM.get_or_init(|| {
+        ProgressStyle::with_template(
+            "[{elapsed_precise}] {spinner:.cyan} {decimal_bytes:>12} {decimal_bytes_per_sec:>14} {msg}",
+        )
+        .unwrap()
+        .tick_chars("⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏◇")
+    })
+}
+
 pub fn item_success_style() -> &'static ProgressStyle {
     static MEM: OnceLock<ProgressStyle> = OnceLock::new();
     MEM.get_or_init(||
@@ -0,0 +1,144 @@
+//! A local HTTP server for tests.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    dev::ServerHandle,
+    http::header::{ACCEPT_RANGES, CONTENT_RANGE, ETAG, IF_RANGE, RANGE},
+    web, App, HttpRequest, HttpResponse, HttpServer,
+};
+
+pub(crate) const ETAG_VALUE: &str = "\"v1\"";
+
+/// What the server sends for every path.
+pub(crate) struct Served {
+    pub data: Vec<u8>,
+    /// Answer range requests with 206; otherwise always send the whole body.
+    pub ranges: AtomicBool,
+    /// Answer this many range requests with 503.
+    pub fail_ranges: AtomicUsize,
+    /// Wait before answering each request.
+    pub delay: Duration,
+    /// Path, `Range` header and arrival time of each request.
+    pub requests: Mutex<Vec<(String, Option<String>, Instant)>>,
+    active: AtomicUsize,
+    /// Most requests waiting on `delay` at once.
+    pub max_active: AtomicUsize,
+}
+
+impl Served {
+    pub fn new(data: Vec<u8>) -> Self {
+        Self {
+            data,
+            ranges: AtomicBool::new(true),
+            fail_ranges: AtomicUsize::new(0),
+            delay: Duration::ZERO,
+            requests: Mutex::default(),
+            active: AtomicUsize::new(0),
+            max_active: AtomicUsize::new(0),
+        }
+    }
+    /// Start measuring `max_active` again from now.
+    pub fn reset_max(&self) {
+        let active = self.active.load(Ordering::SeqCst);
+        self.max_active.store(active, Ordering::SeqCst);
+    }
+    pub fn ranges_requested(&self) -> Vec<String> {
+        let requests = self.requests.lock().unwrap();
+        requests.iter().filter_map(|(_, r, _)| r.clone()).collect()
+    }
+}
+
+pub(crate) struct TestServer {
+    pub url: String,
+    pub served: web::Data<Served>,
+    handle: ServerHandle,
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        // the stop command is sent before the future is polled
+        drop(self.handle.stop(false));
+    }
+}
+
+/// Serve `served` on a free local port. Must be called within a runtime.
+pub(crate) fn serve(served: Served) -> TestServer {
+    let served = web::Data::new(served);
+    let data = served.clone();
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(data.clone())
+            .default_service(web::to(respond))
+    })
+    .workers(1)
+    .bind(("127.0.0.1", 0))
+    .unwrap();
+    let url = format!("http://{}", server.addrs()[0]);
+    let server = server.run();
+    let handle = server.handle();
+    tokio::spawn(server);
+    TestServer {
+        url,
+        served,
+        handle,
+    }
+}
+
+fn parse_range(v: &str, len: usize) -> Option<(usize, usize)> {
+    let (start, end) = v.strip_prefix("bytes=")?.split_once('-')?;
+    let start = start.parse().ok()?;
+    let end = match end {
+        "" => len - 1,
+        e => e.parse::<usize>().ok()?.min(len - 1),
+    };
+    (start <= end).then_some((start, end))
+}
+
+async fn respond(s: web::Data<Served>, req: HttpRequest) -> HttpResponse {
+    let header = |name| req.headers().get(name).and_then(|v| v.to_str().ok());
+    let range = header(RANGE).map(str::to_string);
+    s.requests
+        .lock()
+        .unwrap()
+        .push((req.path().to_string(), range.clone(), Instant::now()));
+    let active = s.active.fetch_add(1, Ordering::SeqCst) + 1;
+    s.max_active.fetch_max(active, Ordering::SeqCst);
+    tokio::time::sleep(s.delay).await;
+    s.active.fetch_sub(1, Ordering::SeqCst);
+
+    let ranges = s.ranges.load(Ordering::SeqCst);
+    let range = range
+        .filter(|_| ranges && header(IF_RANGE).is_none_or(|v| v == ETAG_VALUE))
+        .and_then(|r| parse_range(&r, s.data.len()));
+    if range.is_some()
+        && s.fail_ranges
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok()
+    {
+        return HttpResponse::ServiceUnavailable().finish();
+    }
+    let mut resp = match range {
+        Some(_) => HttpResponse::PartialContent(),
+        None => HttpResponse::Ok(),
+    };
+    resp.insert_header((ETAG, ETAG_VALUE));
+    if ranges {
+        resp.insert_header((ACCEPT_RANGES, "bytes"));
+    }
+    match range {
+        Some((start, end)) => resp
+            .insert_header((
+                CONTENT_RANGE,
+                format!("bytes {start}-{end}/{}", s.data.len()),
+            ))
+            .body(s.data[start..=end].to_vec()),
+        None => resp.body(s.data.clone()),
+    }
+}